@@ -0,0 +1,263 @@
+// Signal processing that doesn't depend on nannou: plain state advanced one sample at a
+// time with the sample period passed in
+
+use serde::{Deserialize, Serialize};
+
+// Shape of the envelope's ramps
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum EnvCurve {
+    #[default]
+    Linear,
+    Exponential,
+}
+
+// How far past its goal an exponential ramp aims, as a fraction of the ramp's height.
+// A small overshoot gives a sharper curve; either way the goal is reached on time.
+const ATTACK_CURVE_OVERSHOOT: f32 = 0.3;
+const DECAY_CURVE_OVERSHOOT: f32 = 0.001;
+
+// One-pole step towards `target` that covers a ramp with the given overshoot in `time`
+fn curve_coefficient(dt: f32, time: f32, overshoot: f32) -> f32 {
+    (-((1.0 + overshoot) / overshoot).ln() * dt / time.max(1e-4)).exp()
+}
+
+// Per-sample coefficient for a one-pole smoother that covers about two thirds of a step in
+// `time` seconds, whatever the sample rate
+pub fn smoothing(time: f32, sample_rate: f32) -> f32 {
+    1.0 - (-1.0 / (time * sample_rate).max(1.0)).exp()
+}
+
+// Moves a gain one sample towards 1 while `on`, or towards 0 otherwise, by a fixed `step`
+// so a fade takes the same time from either end
+pub fn ramp(gain: f32, on: bool, step: f32) -> f32 {
+    let step = if on { step } else { -step };
+    (gain + step).clamp(0.0, 1.0)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnvStage {
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+// Gate-driven ADSR, advanced per sample on the audio thread. Times are in seconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Envelope {
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+    pub stage: EnvStage,
+    pub level: f32,
+    release_from: f32,
+    gate: bool,
+    pub velocity: f32, // Scales the output level, set on each gate
+    pub velocity_amount: f32,
+    pub curve: EnvCurve,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Envelope {
+            attack: 0.05,
+            decay: 0.5,
+            sustain: 0.4,
+            release: 0.25,
+            stage: EnvStage::Idle,
+            level: 0.0,
+            release_from: 0.0,
+            gate: false,
+            velocity: 1.0,
+            velocity_amount: 1.0,
+            curve: EnvCurve::Linear,
+        }
+    }
+
+    // Starts the attack from the current level so retriggering never clicks. With `hold`
+    // the envelope waits at the sustain level for `gate_off`, otherwise it releases
+    // straight after the decay.
+    pub fn gate_on(&mut self, hold: bool, velocity: f32) {
+        self.gate = hold;
+        self.velocity = velocity;
+        self.stage = EnvStage::Attack;
+    }
+
+    // Whether the envelope is waiting on a `gate_off`
+    pub fn is_gated(&self) -> bool {
+        self.gate && self.stage != EnvStage::Idle
+    }
+
+    pub fn gate_off(&mut self) {
+        self.gate = false;
+        if self.stage != EnvStage::Idle {
+            self.start_release();
+        }
+    }
+
+    fn start_release(&mut self) {
+        self.release_from = self.level;
+        self.stage = EnvStage::Release;
+    }
+
+    // Level-driven form of `gate_on`/`gate_off` for callers that hold a gate signal
+    // rather than note events. Edges reuse the last velocity.
+    pub fn process(&mut self, gate: bool, dt: f32) -> f32 {
+        if gate && !self.gate {
+            self.gate_on(true, self.velocity);
+        } else if !gate && self.gate {
+            self.gate_off();
+        }
+        self.next(dt)
+    }
+
+    pub fn next(&mut self, dt: f32) -> f32 {
+        match self.stage {
+            EnvStage::Idle => self.level = 0.0,
+            EnvStage::Attack if self.curve == EnvCurve::Exponential => {
+                let target = 1.0 + ATTACK_CURVE_OVERSHOOT;
+                let coefficient = curve_coefficient(dt, self.attack, ATTACK_CURVE_OVERSHOOT);
+                self.level = target + (self.level - target) * coefficient;
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.stage = EnvStage::Decay;
+                }
+            }
+            EnvStage::Attack => {
+                self.level += dt / self.attack.max(1e-4);
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.stage = EnvStage::Decay;
+                }
+            }
+            EnvStage::Decay => {
+                if self.curve == EnvCurve::Exponential {
+                    let target = self.sustain - DECAY_CURVE_OVERSHOOT * (1.0 - self.sustain);
+                    let coefficient = curve_coefficient(dt, self.decay, DECAY_CURVE_OVERSHOOT);
+                    self.level = target + (self.level - target) * coefficient;
+                } else {
+                    self.level -= (1.0 - self.sustain) * dt / self.decay.max(1e-4);
+                }
+                if self.level <= self.sustain {
+                    self.level = self.sustain;
+                    if self.gate {
+                        self.stage = EnvStage::Sustain;
+                    } else {
+                        self.start_release();
+                    }
+                }
+            }
+            EnvStage::Sustain => self.level = self.sustain,
+            EnvStage::Release => {
+                if self.curve == EnvCurve::Exponential {
+                    let target = -DECAY_CURVE_OVERSHOOT * self.release_from;
+                    let coefficient = curve_coefficient(dt, self.release, DECAY_CURVE_OVERSHOOT);
+                    self.level = target + (self.level - target) * coefficient;
+                } else {
+                    self.level -= self.release_from * dt / self.release.max(1e-4);
+                }
+                if self.level <= 0.0 {
+                    self.level = 0.0;
+                    self.stage = EnvStage::Idle;
+                }
+            }
+        }
+        self.level * (1.0 - self.velocity_amount + self.velocity_amount * self.velocity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 1.0 / 48000.0;
+
+    // Samples in `seconds`, with a little slack for the rounding of f32 steps
+    fn samples(seconds: f32) -> usize {
+        (seconds * 1.001 / DT).ceil() as usize
+    }
+
+    #[test]
+    fn ramp_reaches_its_target_within_the_fade_time() {
+        // A 5 ms fade at 48 kHz
+        let step: f32 = 1.0 / (0.005 * 48000.0);
+        // Rounding in the f32 steps can cost one extra sample
+        let fade = (1.0 / step).ceil() as usize + 1;
+        let mut gain = 0.0;
+        for _ in 0..fade - 2 {
+            gain = ramp(gain, true, step);
+            assert!(gain < 1.0);
+        }
+        for _ in 0..2 {
+            gain = ramp(gain, true, step);
+        }
+        assert_eq!(gain, 1.0);
+        for _ in 0..fade {
+            gain = ramp(gain, false, step);
+        }
+        assert_eq!(gain, 0.0);
+        assert_eq!(ramp(gain, false, step), 0.0);
+    }
+
+    #[test]
+    fn gate_on_runs_attack_then_decay_then_sustain() {
+        for curve in [EnvCurve::Linear, EnvCurve::Exponential] {
+            let mut env = Envelope::new();
+            env.curve = curve;
+            let mut stages = vec![env.stage];
+            for _ in 0..samples(env.attack + env.decay) {
+                env.process(true, DT);
+                if stages.last() != Some(&env.stage) {
+                    stages.push(env.stage);
+                }
+            }
+            let expected = [
+                EnvStage::Idle,
+                EnvStage::Attack,
+                EnvStage::Decay,
+                EnvStage::Sustain,
+            ];
+            assert_eq!(stages, expected);
+            assert_eq!(env.level, env.sustain);
+        }
+    }
+
+    #[test]
+    fn gate_off_from_sustain_reaches_idle_within_release() {
+        for curve in [EnvCurve::Linear, EnvCurve::Exponential] {
+            let mut env = Envelope::new();
+            env.curve = curve;
+            while env.stage != EnvStage::Sustain {
+                env.process(true, DT);
+            }
+            env.process(false, DT);
+            assert_eq!(env.stage, EnvStage::Release);
+            for _ in 0..samples(env.release) {
+                env.process(false, DT);
+            }
+            assert_eq!(env.stage, EnvStage::Idle);
+            assert_eq!(env.level, 0.0);
+        }
+    }
+
+    #[test]
+    fn retrigger_during_release_starts_from_the_current_level() {
+        for curve in [EnvCurve::Linear, EnvCurve::Exponential] {
+            let mut env = Envelope::new();
+            env.curve = curve;
+            while env.stage != EnvStage::Sustain {
+                env.process(true, DT);
+            }
+            for _ in 0..samples(env.release / 2.0) {
+                env.process(false, DT);
+            }
+            let level = env.level;
+            assert!(level > 0.0);
+            env.process(true, DT);
+            assert_eq!(env.stage, EnvStage::Attack);
+            assert!(env.level >= level && env.level - level < 0.01);
+        }
+    }
+}
@@ -1,7 +1,23 @@
+mod dsp;
+
+use dsp::{EnvCurve, EnvStage};
+use midir::{MidiInput, MidiInputConnection};
 use nannou::prelude::*;
 use nannou_audio as audio;
 use nannou_audio::Buffer;
+use nannou_osc as osc;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 fn main() {
     nannou::app(model).update(update).run();
@@ -9,255 +25,4902 @@ fn main() {
 
 struct Model {
     stream: audio::Stream<Audio>,
+    audio_host: audio::Host,
+    output_devices: Vec<String>,  // Output device names found at startup
+    output_device: Option<usize>, // Device the stream plays on, or `None` for the default
     is_mouse_pressed: bool,
     cards: Vec<Card>,
     is_updating: bool,
     grid_slots: Vec<Point2>,
+    grid_cols: usize,             // Slots in each patch row
+    grid_rows: usize,             // Patch rows above the hand: the chain, then the branch
+    grid_size: f32,               // Slot spacing, shrunk when the columns wouldn't fit the window
+    palette: Vec<CardClass>,      // Templates for new cards
     selected_card: Option<usize>, // Index of the selected Card
+    solo: Option<usize>,          // Index of the Card heard alone, if any
+    drag_origin: Point2,          // Where the selected card was picked up from
+    sweep: Option<Sweep>,         // Ctrl+mouse cutoff gesture in progress
+    wheel: Option<(usize, f32)>,  // Card and app time of the last wheel edit
+    inspector_row: usize,         // Focused parameter of the selected card
+    // Card layouts before each action, newest last
+    undo_stack: VecDeque<Vec<Card>>,
+    redo_stack: Vec<Vec<Card>>,
     hand: Vec<Card>,
-    chain: Vec<Card>,
-    bpm: f32,
+    rows: Rows,
+    bpm: f32,   // Internal tempo
+    tempo: f32, // Tempo in use, following MIDI clock when synced
+    clock_source: ClockSource,
+    last_clock_stamp: Option<u64>, // midir timestamp of the last clock pulse, in microseconds
+    last_clock_time: f32,          // App time the last clock pulse arrived
+    pulse_interval: f32,           // Smoothed seconds between clock pulses, 0 until measured
+    midi_running: bool,            // Between MIDI Start/Continue and Stop
     last_update: f32,
     beat_time: f32,
+    beat_crossed: bool, // True only in the frame where a new beat starts
+    beat_step: usize,
+    step_duration: f32, // Length of the current step, swing included
+    beat_delta: f32,    // Beats the clock moved on this frame
+    swing: f32,         // 0.0 is straight time, up to MAX_SWING
+    seed: u64,          // Seeds `rng`, saved with patches so chance plays out the same
+    rng: StdRng,
+    glide_time: f32, // Portamento time in seconds
+    glide: bool,     // Portamento on; when off the pitch jumps whatever the glide time
+    metronome_on: bool,
+    sent_params: SoundParams,    // Last settings handed to the audio thread
+    sample_path: Option<String>, // File last loaded into the sampler
+    mod_routes: Vec<ModRoute>,   // Modulation matrix, beyond each LFO card's own target
+    mic: Option<audio::Stream<OnsetDetector>>, // None without an input device
+    onsets: rtrb::Consumer<()>,
+    events: rtrb::Producer<TimedEvent>,
+    clock: Arc<AudioClock>, // Where the audio thread had got to at its last buffer
+    clock_epoch: Instant,
+    listening: bool, // Onsets from the microphone drive the sequencers and envelope
+    onset: bool,     // True only in the frame an onset arrives
+    snapshot_a: Option<Snapshot>,
+    snapshot_b: Option<Snapshot>,
+    morph: f32,                     // 0 plays snapshot A, 1 plays snapshot B
+    swap: Option<(f32, PatchSwap)>, // Patch change waiting on the output fade, and its start
+    presets: Vec<PathBuf>,          // Patches in the preset folder, by file name
+    preset: Option<usize>,          // Preset last loaded from `presets`
+    taps: VecDeque<f32>,            // App times of the latest tempo taps, oldest first
+    playing: bool, // Transport running; stopped, the beat clock and sequencers hold at the top
+    restart: bool, // Set by Play so the next frame starts on a beat
+    pitch_bend: f32, // Semitones last sent to the audio thread
+    beats_per_bar: usize, // Beats between accented metronome clicks
+    master_volume: f32,
+    wet: f32,   // Global dry/wet mix: 0 skips every effect, 1 plays them fully
+    width: f32, // Stereo width: 0 is mono, 1 unchanged, above 1 wider
+    drive: f32,
+    clipper_bypass: bool,
+    peak: Arc<AtomicU32>,      // Output peak since the last frame, as f32 bits
+    meter_level: f32,          // Decaying peak shown on the meter
+    load: Arc<AtomicU32>,      // Heaviest audio callback since the last frame, as f32 bits
+    cpu_load: f32,             // Smoothed share of the buffer period spent rendering
+    reduction: Arc<AtomicU32>, // Deepest compressor gain reduction since the last frame, in dB
+    reduction_level: f32,      // Decaying gain reduction shown on the compressor's meter
+    reduction_hold: f32,       // Held deepest reduction, marked on the meter
+    reduction_hold_time: f32,  // App time the held reduction was set
+    clip_time: f32,            // App time of the last clip, or negative if none yet
+    recording: bool,
+    scope_consumer: rtrb::Consumer<f32>,
+    scope_samples: VecDeque<f32>, // Most recent output samples, oldest first
+    fft: Arc<dyn Fft<f32>>,
+    fft_buffer: Vec<Complex<f32>>,
+    spectrum: Vec<f32>,  // Smoothed bar heights in 0..1
+    held_notes: Vec<u8>, // Keyboard notes currently held, newest last
+    latch: bool,         // Each note holds until the next one starts
+    quantize: bool,      // Chain changes wait for the next beat while playing
+    pending_drop: bool,  // A quantized chain change is waiting for its beat
+    // Value each mod lane holds its destination at, in the destination's units. Kept here
+    // rather than on the chain copies, which are rebuilt whenever a card moves.
+    lane_levels: Vec<(SeqTarget, f32)>,
+    humanize: f32, // 0 to 1: how far triggered notes stray in timing and velocity
+    // Drawn from for humanizing only, so the chance steps play out as the seed says
+    humanize_rng: StdRng,
+    keyboard_octave: i32, // Octaves the computer keyboard is shifted from middle C
+    keyboard_keys: Vec<(Key, u8)>, // Note each held computer key started, for its release
+    gate: bool,           // True while a played note is held
+    midi_events: Receiver<MidiEvent>,
+    osc_receiver: Option<osc::Receiver>, // None if the port couldn't be opened
+    _midi_connection: Option<MidiInputConnection<()>>, // Input stays open while this is alive
 }
 
+const MAX_SWING: f32 = 0.75;
+const DEFAULT_BPM: f32 = 120.0;
+const MIN_BPM: f32 = 20.0;
+const MAX_BPM: f32 = 300.0;
+// Taps averaged for tap tempo, and the gap after which tapping starts over
+const TAP_HISTORY: usize = 4;
+const TAP_TIMEOUT: f32 = 2.0;
+const MAX_HISTORY: usize = 64;
+// Wheel ticks on the same card closer together than this are undone as one step
+const WHEEL_EDIT_GAP: f32 = 0.5;
+const INSPECTOR_ROWS: usize = 8;
+const DEFAULT_SEED: u64 = 0x5EED;
+// Height of the branch row above the main chain
+const BRANCH_ROW_OFFSET: f32 = 150.0;
+const CLOCK_PULSES_PER_BEAT: f32 = 24.0;
+// Without clock pulses for this long the internal tempo takes over again
+const CLOCK_TIMEOUT: f32 = 0.5;
+// Output samples kept for the oscilloscope, and how many of them are drawn
+const SCOPE_HISTORY: usize = 2048;
+const SCOPE_WIDTH: usize = 512;
+const FFT_SIZE: usize = 1024;
+const SPECTRUM_BARS: usize = 48;
+// A short glide by default takes the edge off pitch jumps
+const DEFAULT_GLIDE_TIME: f32 = 0.01;
+const MAX_GLIDE_TIME: f32 = 2.0;
+// Bend range in semitones either way, reached at the top and bottom of the window
+const PITCH_BEND_RANGE: f32 = 2.0;
+// Smoothing time of pitch bend in seconds, so mouse steps and releases glide
+const PITCH_BEND_SMOOTHING: f32 = 0.011;
+const DEFAULT_MASTER_VOLUME: f32 = 0.5;
+// How long the output fades before the patch is swapped out
+const SWAP_FADE_TIME: f32 = 0.1;
+// Smoothing time of master volume changes in seconds, avoiding zipper noise
+const VOLUME_SMOOTHING: f32 = 0.011;
+// Distance at which the dry/wet mix jumps to its target, so it settles exactly on it
+const WET_SNAP: f32 = 1e-4;
+// Widest the master bus can be spread
+const MAX_WIDTH: f32 = 2.0;
+// Meter fall per second, and how long the clip light stays on
+const METER_DECAY: f32 = 1.5;
+const CLIP_HOLD_TIME: f32 = 1.0;
+// Per-frame smoothing of the CPU readout, and the load shown as a warning
+const CPU_LOAD_SMOOTHING: f32 = 0.1;
+const CPU_LOAD_WARNING: f32 = 0.8;
+// Gain reduction meter fall in dB per second, how long its peak mark holds, and the
+// reduction that fills it
+const REDUCTION_DECAY: f32 = 30.0;
+const REDUCTION_HOLD_TIME: f32 = 1.0;
+const REDUCTION_RANGE: f32 = 24.0;
+// Soft clipper drive settings stepped through with V
+const DRIVE_STEPS: [f32; 4] = [1.0, 2.0, 4.0, 8.0];
+const SOFT_CLIP_KNEE: f32 = 0.5;
+
 struct Audio {
-    phase: f64,
-    hz: f64,
-    playing: bool,
-    envelope: f32,
+    free_hz: f64, // Pitch for beat-triggered notes, wandering while nothing else plays
+    last_hz: f64, // Pitch of the most recent note, which the next one glides from
+    glide_time: f64,
+    voices: [Voice; MAX_VOICES],
+    notes_started: u64, // Used to find the oldest voice to steal
+    phase_seed: u32,    // Scatters each note's unison phases, apart from the noise card's seed
+    voice_gain: f32,    // Headroom for the sounding voices, smoothed as they come and go
+    play_gain: f32,     // Oscillator level, fading in with the first card and out with the last
+    lfo: LfoState,
+    oscillators: [OscillatorState; MAX_OSCILLATORS],
+    noise_color: NoiseColor,
+    noise_amplitude: f32,
+    noise_seed: u32,
+    pink_state: [f32; 3],
+    sampler: SamplerState,
+    filter: FilterState,
+    delay: DelayState,
+    crusher: BitcrushState,
+    vcas: [VcaState; MAX_VCAS],
+    reverb: ReverbState,
+    chorus: ChorusState,
+    eq: EqState,
+    compressor: CompressorState,
+    order: Vec<Stage>,
+    branch: Vec<Stage>, // Mixed into `order` at its mixer stage
+    routes: Vec<ModRoute>,
+    envelope_level: f32, // Loudest voice's envelope on the last sample, a route source
+    mixer_gains: [f32; 2],
+    master_volume: f32, // Target output level in 0..1
+    volume: f32,        // Output level, smoothed towards `master_volume`
+    peak: Arc<AtomicU32>,
+    load: Arc<AtomicU32>,
+    reduction: Arc<AtomicU32>,
+    drive: f32, // Soft clipper input gain, 1.0 and up
+    clipper_bypass: bool,
+    recorder: Option<rtrb::Producer<f32>>,
+    scope: rtrb::Producer<f32>,
+    click: ClickState,
+    pitch_bend_target: f32, // Semitones, set from the UI
+    pitch_bend: f32,        // Semitones, smoothed towards the target
+    wet_target: f32,        // Global dry/wet mix, set from the UI
+    latch: bool,            // Notes hold until the next note-on rather than a key release
+    wet: f32,               // Mix in use, smoothed towards the target
+    width_target: f32,      // Stereo width, set from the UI
+    width: f32,             // Width in use, smoothed towards the target
+    events: rtrb::Consumer<TimedEvent>,
+    frame_clock: u64, // Frames rendered since the stream started
+    clock: Arc<AudioClock>,
+    clock_epoch: Instant,
 }
 
-#[derive(Clone, Debug, PartialEq)]
-struct Oscillator {}
+// A processing step on the audio thread. Sources add to the running signal and
+// processors act on whatever comes before them in the chain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stage {
+    Oscillator(usize),
+    Noise,
+    Sampler,
+    Envelope,
+    Delay,
+    Filter,
+    Mixer,
+    Bitcrush,
+    Reverb,
+    Chorus,
+    Eq,
+    Compressor,
+    Vca(usize),
+}
 
-#[derive(Clone, Debug, PartialEq)]
-struct Sequencer {
-    sequence: Vec<f32>,
-    step: usize,
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Waveform {
+    Sine,
+    Saw,
+    Square,
+    Triangle,
 }
 
-impl Sequencer {
-    fn next_value(&mut self) -> f32 {
-        let value = self.sequence[self.step];
-        self.step = (self.step + 1) % self.sequence.len();
-        value
+impl Waveform {
+    fn next(self) -> Self {
+        match self {
+            Waveform::Sine => Waveform::Saw,
+            Waveform::Saw => Waveform::Square,
+            Waveform::Square => Waveform::Triangle,
+            Waveform::Triangle => Waveform::Sine,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Waveform::Sine => "Sin",
+            Waveform::Saw => "Saw",
+            Waveform::Square => "Sqr",
+            Waveform::Triangle => "Tri",
+        }
+    }
+
+    // Naive sample for a phase in 0..1, used as is by the LFO
+    fn sample(self, phase: f64, pulse_width: f32) -> f32 {
+        let value = match self {
+            Waveform::Sine => (2.0 * PI * phase).sin(),
+            Waveform::Saw => 2.0 * phase - 1.0,
+            Waveform::Square => {
+                if phase < pulse_width as f64 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Triangle => 1.0 - 4.0 * ((phase + 0.25).fract() - 0.5).abs(),
+        };
+        value as f32
+    }
+
+    // Sample with PolyBLEP smoothing of the saw and square jumps, for an oscillator
+    // moving `increment` of a cycle per sample
+    fn band_limited(self, phase: f64, pulse_width: f32, increment: f64) -> f32 {
+        let naive = self.sample(phase, pulse_width) as f64;
+        let value = match self {
+            Waveform::Saw => naive - poly_blep(phase, increment),
+            Waveform::Square => {
+                let fall = (phase - pulse_width as f64).rem_euclid(1.0);
+                naive + poly_blep(phase, increment) - poly_blep(fall, increment)
+            }
+            Waveform::Sine | Waveform::Triangle => naive,
+        };
+        value as f32
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
-struct Envelope {
-    attack: f32,
-    decay: f32,
-    sustain: f32,
-    release: f32,
+// Polynomial correction for a unit jump at phase 0, spread over one sample either side
+// of the wrap. The width follows the increment, so it adapts to pitch and sample rate.
+fn poly_blep(phase: f64, increment: f64) -> f64 {
+    let dt = increment.clamp(0.0, 0.5);
+    if dt == 0.0 {
+        0.0
+    } else if phase < dt {
+        let t = phase / dt;
+        2.0 * t - t * t - 1.0
+    } else if phase > 1.0 - dt {
+        let t = (phase - 1.0) / dt;
+        t * t + 2.0 * t + 1.0
+    } else {
+        0.0
+    }
 }
 
-#[derive(Clone, Debug, PartialEq)]
-struct Delay {
-    delay_time: f32,
-    feedback: f32,
-    wet: f32,
-    buffer: Vec<f32>,
-    write_index: usize,
+const MAX_OSCILLATORS: usize = 4;
+// Time taken for an oscillator card to fade in or out, in seconds
+const OSCILLATOR_FADE_TIME: f64 = 0.005;
+
+// Audio-thread state for one oscillator card in the chain. Each voice keeps its own
+// phase for it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct OscillatorState {
+    waveform: Waveform,
+    pulse_width: f32,
+    active: bool,
+    gain: f32,
+    fm: Option<ModOsc>,
+    sub_level: f32,
+    sub_octaves: u8,
+    unison: usize,
+    detune_ratios: [f64; MAX_UNISON], // Pitch of each unison copy relative to the voice
+    pan_gains: [f32; 2],
 }
 
-#[derive(Clone, Debug, PartialEq)]
-enum CardClass {
-    Oscillator(Oscillator),
-    Sequencer(Sequencer),
-    Envelope(Envelope),
-    Delay(Delay),
-    // Add more variants here as needed
+impl OscillatorState {
+    fn new() -> Self {
+        OscillatorState {
+            waveform: Waveform::Sine,
+            pulse_width: 0.5,
+            active: false,
+            gain: 0.0,
+            fm: None,
+            sub_level: 0.0,
+            sub_octaves: 1,
+            unison: 1,
+            detune_ratios: [1.0; MAX_UNISON],
+            pan_gains: [1.0; 2],
+        }
+    }
+
+    // Speed of the FM modulator relative to the carrier, or zero without one
+    fn fm_ratio(&self) -> f64 {
+        self.fm.map_or(0.0, |fm| fm.ratio as f64)
+    }
+
+    // Moves the gain one sample towards full level while active, or silence otherwise
+    fn fade(&mut self, step: f32) {
+        self.gain = dsp::ramp(self.gain, self.active, step);
+    }
+
+    // The modulator only offsets where the carrier is read, never its accumulator, so
+    // wrapping the result keeps any index in range
+    fn sample(&self, voice: &Voice, index: usize) -> f32 {
+        let phase = voice.phases[index];
+        let increment = voice.increment;
+        let offset = match self.fm {
+            Some(fm) => {
+                let mod_phase = voice.mod_phases[index];
+                fm.index as f64 * (2.0 * PI * mod_phase).sin() / (2.0 * PI)
+            }
+            None => 0.0,
+        };
+        let mut value = if self.unison > 1 {
+            let copies = voice.unison_phases[index].iter().zip(self.detune_ratios);
+            let sum: f32 = copies
+                .take(self.unison)
+                .map(|(&copy, ratio)| self.carrier(copy, offset, increment * ratio))
+                .sum();
+            sum / (self.unison as f32).sqrt()
+        } else {
+            self.carrier(phase, offset, increment)
+        };
+        if self.sub_level > 0.0 {
+            // The sub is read from the main phase and its cycle count, so it stays
+            // locked to the main oscillator
+            let span = 1u8 << self.sub_octaves;
+            let sub_phase = ((voice.cycles[index] % span) as f64 + phase) / span as f64;
+            let sub_increment = increment / span as f64;
+            value += Waveform::Square.band_limited(sub_phase, 0.5, sub_increment) * self.sub_level;
+        }
+        value * self.gain
+    }
+
+    fn carrier(&self, phase: f64, fm_offset: f64, increment: f64) -> f32 {
+        let phase = if self.fm.is_some() {
+            (phase + fm_offset).rem_euclid(1.0)
+        } else {
+            phase
+        };
+        self.waveform
+            .band_limited(phase, self.pulse_width, increment)
+    }
 }
 
-#[derive(Clone, Debug, PartialEq)]
-struct Card {
-    x: f32,
-    x_last: f32,
-    x_targ: f32,
-    y: f32,
-    y_last: f32,
-    y_targ: f32,
-    w: f32,
-    h: f32,
-    dragging: bool,
-    rotation: f32,
-    scale: f32,
-    start_time: f32,
-    class: CardClass,
+// Equal-power left and right gains for a pan position in -1..1, scaled so the centre
+// stays at unity in both channels
+fn pan_gains(pan: f32) -> [f32; 2] {
+    let angle = (pan.clamp(-1.0, 1.0) + 1.0) * std::f32::consts::FRAC_PI_4;
+    [angle.cos(), angle.sin()].map(|gain| gain * std::f32::consts::SQRT_2)
 }
 
-impl Card {
-    fn default(class: CardClass) -> Self {
-        Card {
-            x: 0.0,
-            x_last: 0.0,
-            x_targ: 0.0,
-            y: 0.0,
-            y_last: 0.0,
-            y_targ: 0.0,
-            w: 100.0,
-            h: 140.0,
-            dragging: false,
-            rotation: 0.0,
-            scale: 1.0,
-            start_time: 0.0,
-            class,
+// Spreads `count` copies evenly between -detune and +detune cents around the voice pitch
+fn detune_ratios(count: usize, detune_cents: f32) -> [f64; MAX_UNISON] {
+    let mut ratios = [1.0; MAX_UNISON];
+    if count > 1 {
+        for (i, ratio) in ratios.iter_mut().take(count).enumerate() {
+            let position = 2.0 * i as f64 / (count - 1) as f64 - 1.0;
+            *ratio = (position * detune_cents as f64 / 1200.0).exp2();
         }
     }
+    ratios
+}
 
-    fn new(x: f32, y: f32, class: CardClass) -> Self {
-        let mut card = Card::default(class);
-        card.x = x;
-        card.x_last = x;
-        card.x_targ = x;
-        card.y = y;
-        card.y_last = y;
-        card.y_targ = y;
-        card
-    }
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Oscillator {
+    waveform: Waveform,
+    pulse_width: f32,
+    // Sine modulator that turns the oscillator into an FM operator
+    #[serde(default)]
+    modulator: Option<Box<ModOsc>>,
+    // Square wave an octave or two below, mixed in at this level
+    #[serde(default)]
+    sub_level: f32,
+    #[serde(default = "default_sub_octaves")]
+    sub_octaves: u8,
+    // Detuned copies of the oscillator, spread over +/- detune_cents
+    #[serde(default = "default_unison")]
+    unison: u8,
+    #[serde(default = "default_detune_cents")]
+    detune_cents: f32,
+    #[serde(default)]
+    pan: f32, // -1.0 is hard left, 1.0 hard right
 }
 
-fn model(app: &App) -> Model {
-    app.new_window()
-        .key_pressed(key_pressed)
-        .mouse_pressed(mouse_pressed)
-        .mouse_released(mouse_released)
-        .view(view)
-        .build()
-        .unwrap();
+fn default_sub_octaves() -> u8 {
+    1
+}
 
-    let audio_host = audio::Host::new();
+fn default_unison() -> u8 {
+    1
+}
 
-    let audio_model = Audio {
-        phase: 0.0,
-        hz: 440.0,
-        playing: false,
-        envelope: 0.0,
-    };
+fn default_detune_cents() -> f32 {
+    10.0
+}
 
-    let stream = audio_host
-        .new_output_stream(audio_model)
-        .render(audio)
-        .build()
-        .unwrap();
+const MAX_SUB_OCTAVES: u8 = 2;
+const MAX_UNISON: usize = 7;
+const MAX_DETUNE_CENTS: f32 = 100.0;
 
-    stream.play().unwrap();
+// Modulator frequency as a multiple of the carrier's, and modulation index in radians
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct ModOsc {
+    ratio: f32,
+    index: f32,
+}
 
-    let grid_slots = create_grid_slots(app.window_rect(), 110.0, 5);
+const MIN_FM_RATIO: f32 = 0.125;
+const MAX_FM_RATIO: f32 = 16.0;
+const MAX_FM_INDEX: f32 = 20.0;
 
-    Model {
-        stream,
-        is_mouse_pressed: false,
-        cards: vec![
-            Card::new(0.0, 0.0, CardClass::Oscillator(Oscillator {})),
-            Card::new(
-                100.0,
-                100.0,
-                CardClass::Sequencer(Sequencer {
-                    sequence: vec![0.8, 1.0, 1.2, 1.0],
-                    step: 0,
-                }),
-            ),
-            Card::new(
-                200.0,
-                200.0,
-                CardClass::Envelope(Envelope {
-                    attack: 0.1,
-                    decay: 1.0,
-                    sustain: 0.4,
-                    release: 0.5,
-                }),
-            ),
-            Card::new(
-                300.0,
-                300.0,
-                CardClass::Delay(Delay {
-                    delay_time: 0.5,
-                    feedback: 0.5,
-                    wet: 0.5,
-                    buffer: vec![0.0; 44100], // 1 second buffer at 44100 Hz sample rate
-                    write_index: 0,
-                }),
-            ),
-        ],
-        is_updating: false,
-        grid_slots,
-        selected_card: None,
-        hand: vec![],
-        chain: vec![],
-        bpm: 120.0,
-        last_update: 0.0,
-        beat_time: 0.0,
-    }
+// Keep the duty cycle away from 0 and 1, which would silence the square
+const MIN_PULSE_WIDTH: f32 = 0.05;
+const MAX_PULSE_WIDTH: f32 = 0.95;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum NoiseColor {
+    White,
+    Pink,
 }
 
-fn create_grid_slots(win: Rect, grid_size: f32, num_slots: usize) -> Vec<Point2> {
-    let mut grid_slots = vec![];
-    let middle_y = win.bottom() + win.h() / 2.0;
-    for i in 0..num_slots {
-        let x = win.left() + 2.6 * grid_size + i as f32 * grid_size;
-        grid_slots.push(pt2(x, middle_y));
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Noise {
+    color: NoiseColor,
+    amplitude: f32,
+}
+
+// Xorshift32: cheap, allocation-free and lock-free, safe to call on the audio thread
+fn white_noise(seed: &mut u32) -> f32 {
+    *seed ^= *seed << 13;
+    *seed ^= *seed >> 17;
+    *seed ^= *seed << 5;
+    (*seed as f32 / u32::MAX as f32) * 2.0 - 1.0
+}
+
+// Paul Kellet's economy pink noise filter applied to a white noise sample
+fn pink_noise(white: f32, state: &mut [f32; 3]) -> f32 {
+    state[0] = 0.99765 * state[0] + white * 0.0990460;
+    state[1] = 0.96300 * state[1] + white * 0.2965164;
+    state[2] = 0.57000 * state[2] + white * 1.0526913;
+    (state[0] + state[1] + state[2] + white * 0.1848) * 0.25
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Sampler {
+    path: String, // WAV file, read when the card first reaches the chain
+    loop_mode: bool,
+    pitch: f32, // Playback speed, 1.0 plays the file as recorded
+}
+
+const SAMPLE_PATH: &str = "sample.wav";
+const MIN_SAMPLER_PITCH: f32 = 0.25;
+const MAX_SAMPLER_PITCH: f32 = 4.0;
+// Length of the crossfade that hides the loop point, in file samples
+const SAMPLER_LOOP_FADE: usize = 256;
+
+// Sample playback state, owned by the audio thread. The buffer holds the file mixed down
+// to mono at its own rate, and is read at whatever speed reaches the output rate.
+struct SamplerState {
+    buffer: Vec<f32>,
+    rate: f32, // Sample rate of the file
+    sample_rate: f32,
+    pitch: f32,
+    loop_mode: bool,
+    position: f64, // Read position in file samples
+    playing: bool,
+    looped: bool, // Past the first pass, so the loop start fades in from the loop end
+}
+
+impl SamplerState {
+    fn new() -> Self {
+        SamplerState {
+            buffer: vec![],
+            rate: 44100.0,
+            sample_rate: 44100.0,
+            pitch: 1.0,
+            loop_mode: false,
+            position: 0.0,
+            playing: false,
+            looped: false,
+        }
     }
-    let bottom_y = win.bottom() + grid_size;
-    for i in 0..num_slots {
-        let x = win.left() + 2.6 * grid_size + i as f32 * grid_size;
-        grid_slots.push(pt2(x, bottom_y));
+
+    fn load(&mut self, buffer: Vec<f32>, rate: f32) {
+        self.buffer = buffer;
+        self.rate = rate;
+        self.playing = false;
+    }
+
+    // Plays from the top, on every note and beat trigger
+    fn trigger(&mut self) {
+        self.position = 0.0;
+        self.playing = !self.buffer.is_empty();
+        self.looped = false;
+    }
+
+    fn next(&mut self) -> f32 {
+        if !self.playing {
+            return 0.0;
+        }
+        // Looping stops short of the end, keeping the tail to crossfade into the start
+        let len = self.buffer.len();
+        let fade = SAMPLER_LOOP_FADE.min(len / 4);
+        let end = if self.loop_mode { len - fade } else { len };
+        let mut value = self.read(self.position);
+        if self.looped && self.position < fade as f64 {
+            let t = (self.position / fade as f64) as f32;
+            value = value * t + self.read(self.position + end as f64) * (1.0 - t);
+        }
+        self.position += (self.pitch * self.rate / self.sample_rate) as f64;
+        if self.position >= end as f64 {
+            if self.loop_mode {
+                self.position %= end as f64;
+                self.looped = true;
+            } else {
+                self.playing = false;
+            }
+        }
+        value
+    }
+
+    // Linear interpolation between neighbouring samples, with silence past the end
+    fn read(&self, position: f64) -> f32 {
+        let index = position as usize;
+        let frac = (position - index as f64) as f32;
+        let a = self.buffer.get(index).copied().unwrap_or(0.0);
+        let b = self.buffer.get(index + 1).copied().unwrap_or(0.0);
+        a + (b - a) * frac
     }
-    grid_slots
 }
 
-fn audio(audio: &mut Audio, buffer: &mut Buffer) {
-    let sample_rate = buffer.sample_rate() as f64;
-    let max_volume = 0.5;
-    let volume = if audio.playing {
-        max_volume * audio.envelope.min(1.0)
-    } else {
-        0.0
+// Reads a WAV file as mono samples, averaging its channels, along with its sample rate
+fn load_sample(path: &str) -> Result<(Vec<f32>, u32), String> {
+    let mut reader = hound::WavReader::open(path).map_err(|err| err.to_string())?;
+    let spec = reader.spec();
+    let samples: Result<Vec<f32>, _> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect(),
+        hound::SampleFormat::Int => {
+            let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|s| s as f32 * scale))
+                .collect()
+        }
     };
+    let samples = samples.map_err(|err| err.to_string())?;
+    let channels = spec.channels.max(1) as usize;
+    let mono = samples
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect();
+    Ok((mono, spec.sample_rate))
+}
 
-    for frame in buffer.frames_mut() {
-        let sine_amp = (2.0 * PI * audio.phase).sin() as f32;
-        audio.phase += audio.hz / sample_rate;
-        if audio.phase >= 1.0 {
-            audio.phase -= 1.0;
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[allow(clippy::enum_variant_names)]
+enum FilterMode {
+    LowPass,
+    HighPass,
+    BandPass,
+}
+
+impl FilterMode {
+    fn next(self) -> Self {
+        match self {
+            FilterMode::LowPass => FilterMode::HighPass,
+            FilterMode::HighPass => FilterMode::BandPass,
+            FilterMode::BandPass => FilterMode::LowPass,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            FilterMode::LowPass => "LP",
+            FilterMode::HighPass => "HP",
+            FilterMode::BandPass => "BP",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Filter {
+    cutoff: f32,
+    resonance: f32,
+    mode: FilterMode,
+}
+
+// Smoothing time of the filter coefficients in seconds
+const FILTER_COEFF_SMOOTHING: f32 = 0.0005;
+// Time taken to crossfade between filter modes, in seconds
+const FILTER_MODE_FADE_TIME: f32 = 0.0015;
+
+// Topology-preserving state variable filter (Simper), run on the audio thread
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FilterState {
+    cutoff: f32,
+    resonance: f32,
+    mode: FilterMode,
+    prev_mode: FilterMode,
+    mode_fade: f32,
+    mode_fade_step: f32,
+    sample_rate: f32,
+    smoothing: f32, // Coefficient smoothing at the current sample rate
+    dirty: bool,
+    fresh: bool,
+    g: f32,
+    k: f32,
+    target_g: f32,
+    target_k: f32,
+    ic1eq: [f32; 2],
+    ic2eq: [f32; 2],
+    cutoff_mod: f32, // Octaves added to the cutoff for the next sample
+}
+
+impl FilterState {
+    fn new() -> Self {
+        FilterState {
+            cutoff: 1000.0,
+            resonance: 0.0,
+            mode: FilterMode::LowPass,
+            prev_mode: FilterMode::LowPass,
+            mode_fade: 1.0,
+            mode_fade_step: 1.0,
+            sample_rate: 0.0,
+            smoothing: 1.0,
+            dirty: true,
+            fresh: true,
+            g: 0.0,
+            k: 2.0,
+            target_g: 0.0,
+            target_k: 2.0,
+            ic1eq: [0.0; 2],
+            ic2eq: [0.0; 2],
+            cutoff_mod: 0.0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ic1eq = [0.0; 2];
+        self.ic2eq = [0.0; 2];
+        self.mode_fade = 1.0;
+        self.fresh = true;
+    }
+
+    fn set_params(&mut self, cutoff: f32, resonance: f32, mode: FilterMode) {
+        if cutoff != self.cutoff || resonance != self.resonance {
+            self.cutoff = cutoff;
+            self.resonance = resonance;
+            self.dirty = true;
+        }
+        if mode != self.mode {
+            self.prev_mode = self.mode;
+            self.mode = mode;
+            self.mode_fade = 0.0;
+        }
+    }
+
+    // Recomputes the coefficient targets only when the parameters or sample rate change,
+    // keeping the cutoff below Nyquist
+    fn update_coefficients(&mut self, sample_rate: f32) {
+        if self.dirty || sample_rate != self.sample_rate {
+            let cutoff = self.cutoff.clamp(20.0, 0.49 * sample_rate);
+            self.target_g = (std::f32::consts::PI * cutoff / sample_rate).tan();
+            self.target_k = 2.0 - 1.98 * self.resonance.clamp(0.0, 1.0);
+            self.sample_rate = sample_rate;
+            self.smoothing = dsp::smoothing(FILTER_COEFF_SMOOTHING, sample_rate);
+            self.mode_fade_step = 1.0 / (FILTER_MODE_FADE_TIME * sample_rate).max(1.0);
+            self.dirty = false;
+        }
+        if self.fresh {
+            self.g = self.target_g;
+            self.k = self.target_k;
+            self.fresh = false;
+        }
+    }
+
+    fn process(&mut self, input: [f32; 2]) -> [f32; 2] {
+        self.g += (self.target_g - self.g) * self.smoothing;
+        self.k += (self.target_k - self.k) * self.smoothing;
+        // Modulated cutoffs change every sample, so they skip the smoothing
+        let g = if self.cutoff_mod != 0.0 {
+            let cutoff =
+                (self.cutoff * self.cutoff_mod.exp2()).clamp(20.0, 0.49 * self.sample_rate);
+            (std::f32::consts::PI * cutoff / self.sample_rate).tan()
+        } else {
+            self.g
+        };
+        let k = self.k;
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+        if self.mode_fade < 1.0 {
+            self.mode_fade = (self.mode_fade + self.mode_fade_step).min(1.0);
+        }
+
+        let mut output = [0.0; 2];
+        for (ch, out) in output.iter_mut().enumerate() {
+            let x = input[ch];
+            let v3 = x - self.ic2eq[ch];
+            let v1 = a1 * self.ic1eq[ch] + a2 * v3;
+            let v2 = self.ic2eq[ch] + a2 * self.ic1eq[ch] + a3 * v3;
+            self.ic1eq[ch] = 2.0 * v1 - self.ic1eq[ch];
+            self.ic2eq[ch] = 2.0 * v2 - self.ic2eq[ch];
+
+            let select = |mode| match mode {
+                FilterMode::LowPass => v2,
+                FilterMode::HighPass => x - k * v1 - v2,
+                FilterMode::BandPass => v1,
+            };
+            // Crossfade from the previous mode so switching doesn't produce a transient
+            let previous = select(self.prev_mode);
+            *out = previous + (select(self.mode) - previous) * self.mode_fade;
+        }
+        output
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Equalizer {
+    low: f32, // Band gains in dB
+    mid: f32,
+    high: f32,
+    mid_freq: f32, // Hz
+}
+
+const EQ_MAX_GAIN: f32 = 12.0;
+const EQ_LOW_FREQ: f32 = 200.0;
+const EQ_HIGH_FREQ: f32 = 4000.0;
+const EQ_MIN_MID_FREQ: f32 = 100.0;
+const EQ_MAX_MID_FREQ: f32 = 8000.0;
+const EQ_MID_Q: f32 = 1.0;
+// Smoothing time of the band coefficients in seconds; they snap once close
+const EQ_COEFF_SMOOTHING: f32 = 0.002;
+const EQ_COEFF_SNAP: f32 = 1e-6;
+// Coefficients that pass the input straight through
+const EQ_FLAT: [f32; 5] = [1.0, 0.0, 0.0, 0.0, 0.0];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum EqShape {
+    LowShelf,
+    Peak,
+    HighShelf,
+}
+
+// Biquad coefficients [b0, b1, b2, a1, a2] from the RBJ cookbook, normalised by a0. A flat
+// band gets exactly the pass-through set.
+fn eq_coefficients(shape: EqShape, freq: f32, gain: f32, sample_rate: f32) -> [f32; 5] {
+    if gain == 0.0 {
+        return EQ_FLAT;
+    }
+    let a = 10f32.powf(gain / 40.0);
+    let w = 2.0 * std::f32::consts::PI * freq.min(0.49 * sample_rate) / sample_rate;
+    let (sin, cos) = w.sin_cos();
+    let [b0, b1, b2, a0, a1, a2] = match shape {
+        EqShape::Peak => {
+            let alpha = sin / (2.0 * EQ_MID_Q);
+            [
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ]
+        }
+        // Shelves use a slope of 1, the steepest without a bump
+        EqShape::LowShelf => {
+            let sq = 2.0 * a.sqrt() * sin / std::f32::consts::SQRT_2;
+            [
+                a * ((a + 1.0) - (a - 1.0) * cos + sq),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - sq),
+                (a + 1.0) + (a - 1.0) * cos + sq,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - sq,
+            ]
         }
-        for channel in frame {
-            *channel = sine_amp * volume as f32;
+        EqShape::HighShelf => {
+            let sq = 2.0 * a.sqrt() * sin / std::f32::consts::SQRT_2;
+            [
+                a * ((a + 1.0) + (a - 1.0) * cos + sq),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - sq),
+                (a + 1.0) - (a - 1.0) * cos + sq,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - sq,
+            ]
+        }
+    };
+    [b0 / a0, b1 / a0, b2 / a0, a1 / a0, a2 / a0]
+}
+
+// One direct form I biquad band, in stereo
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct EqBand {
+    coeffs: [f32; 5],
+    target: [f32; 5],
+    x: [[f32; 2]; 2], // Last two inputs per channel, newest first
+    y: [[f32; 2]; 2], // Last two outputs per channel
+}
+
+impl EqBand {
+    fn new() -> Self {
+        EqBand {
+            coeffs: EQ_FLAT,
+            target: EQ_FLAT,
+            x: [[0.0; 2]; 2],
+            y: [[0.0; 2]; 2],
         }
     }
+
+    fn process(&mut self, input: [f32; 2], smoothing: f32) -> [f32; 2] {
+        for (c, t) in self.coeffs.iter_mut().zip(self.target) {
+            *c += (t - *c) * smoothing;
+            if (t - *c).abs() < EQ_COEFF_SNAP {
+                *c = t;
+            }
+        }
+        let [b0, b1, b2, a1, a2] = self.coeffs;
+        let mut output = [0.0; 2];
+        for (ch, out) in output.iter_mut().enumerate() {
+            let x = input[ch];
+            let [x1, x2] = self.x[ch];
+            let [y1, y2] = self.y[ch];
+            *out = b0 * x + b1 * x1 + b2 * x2 - a1 * y1 - a2 * y2;
+            self.x[ch] = [x, x1];
+            self.y[ch] = [*out, y1];
+        }
+        output
+    }
+}
+
+// Three-band EQ run on the audio thread: low shelf, mid peak and high shelf in series
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct EqState {
+    gains: [f32; 3], // dB, low to high
+    mid_freq: f32,
+    sample_rate: f32,
+    smoothing: f32, // Coefficient smoothing at the current sample rate
+    dirty: bool,
+    fresh: bool,
+    bands: [EqBand; 3],
+}
+
+impl EqState {
+    fn new() -> Self {
+        EqState {
+            gains: [0.0; 3],
+            mid_freq: 1000.0,
+            sample_rate: 0.0,
+            smoothing: 1.0,
+            dirty: true,
+            fresh: true,
+            bands: [EqBand::new(); 3],
+        }
+    }
+
+    fn reset(&mut self) {
+        for band in self.bands.iter_mut() {
+            band.x = [[0.0; 2]; 2];
+            band.y = [[0.0; 2]; 2];
+        }
+        self.fresh = true;
+    }
+
+    fn set_params(&mut self, gains: [f32; 3], mid_freq: f32) {
+        if gains != self.gains || mid_freq != self.mid_freq {
+            self.gains = gains;
+            self.mid_freq = mid_freq;
+            self.dirty = true;
+        }
+    }
+
+    // Recomputes the band targets only when the settings or sample rate change
+    fn update_coefficients(&mut self, sample_rate: f32) {
+        if self.dirty || sample_rate != self.sample_rate {
+            let shapes = [
+                (EqShape::LowShelf, EQ_LOW_FREQ),
+                (EqShape::Peak, self.mid_freq),
+                (EqShape::HighShelf, EQ_HIGH_FREQ),
+            ];
+            for ((band, (shape, freq)), gain) in self.bands.iter_mut().zip(shapes).zip(self.gains) {
+                band.target = eq_coefficients(shape, freq, gain, sample_rate);
+            }
+            self.sample_rate = sample_rate;
+            self.smoothing = dsp::smoothing(EQ_COEFF_SMOOTHING, sample_rate);
+            self.dirty = false;
+        }
+        if self.fresh {
+            for band in self.bands.iter_mut() {
+                band.coeffs = band.target;
+            }
+            self.fresh = false;
+        }
+    }
+
+    fn process(&mut self, input: [f32; 2]) -> [f32; 2] {
+        self.bands
+            .iter_mut()
+            .fold(input, |signal, band| band.process(signal, self.smoothing))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Compressor {
+    threshold: f32, // dB
+    ratio: f32,     // Input dB over the threshold per output dB, 1 and up
+    attack: f32,    // Seconds
+    release: f32,
+    makeup: f32, // dB
+}
+
+const MIN_COMP_THRESHOLD: f32 = -60.0;
+const MAX_COMP_RATIO: f32 = 20.0;
+const MIN_COMP_ATTACK: f32 = 0.0005;
+const MAX_COMP_ATTACK: f32 = 0.2;
+const MIN_COMP_RELEASE: f32 = 0.01;
+const MAX_COMP_RELEASE: f32 = 2.0;
+const MAX_COMP_MAKEUP: f32 = 24.0;
+
+// Compressor run on the audio thread. Both channels share one peak detector so the
+// stereo image holds still while the gain moves.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct CompressorState {
+    threshold: f32,
+    ratio: f32,
+    attack: f32,
+    release: f32,
+    makeup: f32,
+    sample_rate: f32,
+    envelope: f32,       // Detected peak level, linear
+    reduction: f32,      // Gain reduction being applied, in dB
+    peak_reduction: f32, // Deepest reduction this buffer, reported to the meter
+}
+
+impl CompressorState {
+    fn new() -> Self {
+        CompressorState {
+            threshold: -18.0,
+            ratio: 4.0,
+            attack: 0.01,
+            release: 0.2,
+            makeup: 0.0,
+            sample_rate: 44100.0,
+            envelope: 0.0,
+            reduction: 0.0,
+            peak_reduction: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: [f32; 2]) -> [f32; 2] {
+        // The detector rises at the attack rate and falls at the release rate, so the gain
+        // glides rather than steps on a sudden transient
+        let level = input[0].abs().max(input[1].abs());
+        let time = if level > self.envelope {
+            self.attack
+        } else {
+            self.release
+        };
+        let coefficient = 1.0 - (-1.0 / (time * self.sample_rate)).exp();
+        self.envelope += (level - self.envelope) * coefficient;
+        let over = 20.0 * self.envelope.max(1e-6).log10() - self.threshold;
+        self.reduction = over.max(0.0) * (1.0 - 1.0 / self.ratio);
+        self.peak_reduction = self.peak_reduction.max(self.reduction);
+        let gain = 10f32.powf((self.makeup - self.reduction) / 20.0);
+        input.map(|x| x * gain)
+    }
+}
+
+// What a modulation route moves
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum ModDest {
+    Pitch,
+    Cutoff,
+    Amplitude,
+    Pan,
+}
+
+impl ModDest {
+    fn next(self) -> Self {
+        match self {
+            ModDest::Pitch => ModDest::Cutoff,
+            ModDest::Cutoff => ModDest::Amplitude,
+            ModDest::Amplitude => ModDest::Pan,
+            ModDest::Pan => ModDest::Pitch,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            ModDest::Pitch => "Pch",
+            ModDest::Cutoff => "Cut",
+            ModDest::Amplitude => "Amp",
+            ModDest::Pan => "Pan",
+        }
+    }
+}
+
+// What drives a modulation route, each backed by a card in the chain
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum ModSource {
+    Lfo,
+    Envelope, // Loudest voice's envelope
+}
+
+impl ModSource {
+    fn of(class: &CardClass) -> Option<ModSource> {
+        match class {
+            CardClass::Lfo(_) => Some(ModSource::Lfo),
+            CardClass::Envelope(_) => Some(ModSource::Envelope),
+            _ => None,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            ModSource::Lfo => "LFO",
+            ModSource::Envelope => "Env",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct ModRoute {
+    source: ModSource,
+    dest: ModDest,
+    amount: f32, // -1..1, a share of the destination's full range
+}
+
+// Amounts Shift+K steps a route through
+const MOD_AMOUNT_STEPS: [f32; 4] = [0.5, 1.0, -0.5, -1.0];
+
+// Route totals for each destination, each held to -1..1 however many routes share it
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct ModAmounts {
+    pitch: f32,
+    cutoff: f32,
+    amplitude: f32,
+    pan: f32,
+}
+
+fn resolve_routes(routes: &[ModRoute], lfo: f32, envelope: f32) -> ModAmounts {
+    let mut mods = ModAmounts::default();
+    for route in routes {
+        let value = match route.source {
+            ModSource::Lfo => lfo,
+            ModSource::Envelope => envelope,
+        } * route.amount;
+        match route.dest {
+            ModDest::Pitch => mods.pitch += value,
+            ModDest::Cutoff => mods.cutoff += value,
+            ModDest::Amplitude => mods.amplitude += value,
+            ModDest::Pan => mods.pan += value,
+        }
+    }
+    ModAmounts {
+        pitch: mods.pitch.clamp(-1.0, 1.0),
+        cutoff: mods.cutoff.clamp(-1.0, 1.0),
+        amplitude: mods.amplitude.clamp(-1.0, 1.0),
+        pan: mods.pan.clamp(-1.0, 1.0),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Lfo {
+    rate: f32,  // Hz
+    depth: f32, // 0..1
+    shape: Waveform,
+    target: ModDest, // Routed at full strength, alongside any matrix routes
+}
+
+// Slow sweeps up to audio rate, where pitch modulation turns into FM
+const MIN_LFO_RATE: f32 = 0.01;
+const MAX_LFO_RATE: f32 = 500.0;
+// Modulation range at full strength, in octaves either side of the set value
+const MOD_PITCH_OCTAVES: f32 = 1.0;
+const MOD_CUTOFF_OCTAVES: f32 = 4.0;
+
+// Audio-thread state for the LFO card in the chain
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct LfoState {
+    phase: f64,
+    rate: f32,
+    depth: f32,
+    shape: Waveform,
+    active: bool,
+}
+
+impl LfoState {
+    fn new() -> Self {
+        LfoState {
+            phase: 0.0,
+            rate: 1.0,
+            depth: 0.0,
+            shape: Waveform::Sine,
+            active: false,
+        }
+    }
+
+    // Returns the output in -depth..depth, advancing the phase by one sample
+    fn next(&mut self, sample_rate: f64) -> f32 {
+        if !self.active {
+            return 0.0;
+        }
+        let value = self.shape.sample(self.phase, 0.5) * self.depth;
+        self.phase = (self.phase + self.rate as f64 / sample_rate).fract();
+        value
+    }
+}
+
+// Plain gain stage for setting levels between effects
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Vca {
+    gain: f32,
+}
+
+const MAX_VCAS: usize = 4;
+const MAX_VCA_GAIN: f32 = 4.0;
+
+// Gain smoothed towards the card's setting, landing on it exactly so unity gain is
+// transparent
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct VcaState {
+    target: f32,
+    gain: f32,
+    smoothing: f32, // Set each buffer for the current sample rate
+}
+
+impl VcaState {
+    fn new() -> Self {
+        VcaState {
+            target: 1.0,
+            gain: 1.0,
+            smoothing: 1.0,
+        }
+    }
+
+    fn process(&mut self, input: [f32; 2]) -> [f32; 2] {
+        self.gain = if (self.target - self.gain).abs() < WET_SNAP {
+            self.target
+        } else {
+            self.gain + (self.target - self.gain) * self.smoothing
+        };
+        input.map(|x| x * self.gain)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Bitcrush {
+    bits: u8,        // 1..=16
+    downsample: u32, // Frames each sample is held for
+}
+
+const MAX_DOWNSAMPLE: u32 = 64;
+
+// Bit-depth and sample-rate reduction. The hold counter lives here so it carries over
+// from one buffer to the next.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct BitcrushState {
+    bits: u8,
+    downsample: u32,
+    counter: u32,
+    hold: [f32; 2],
+}
+
+impl BitcrushState {
+    fn new() -> Self {
+        BitcrushState {
+            bits: 16,
+            downsample: 1,
+            counter: 0,
+            hold: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, input: [f32; 2]) -> [f32; 2] {
+        if self.counter == 0 {
+            // Half the levels either side of zero
+            let levels = (1u32 << (self.bits - 1)) as f32;
+            self.hold = input.map(|x| (x * levels).round() / levels);
+        }
+        self.counter = (self.counter + 1) % self.downsample;
+        self.hold
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Reverb {
+    room_size: f32,
+    damping: f32,
+    wet: f32,
+}
+
+// Freeverb delay lengths in samples at 44.1kHz, scaled to the running rate
+const REVERB_COMB_TUNINGS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const REVERB_ALLPASS_TUNINGS: [usize; 4] = [556, 441, 341, 225];
+// Extra delay on the right channel, decorrelating it from the left
+const REVERB_STEREO_SPREAD: usize = 23;
+// Buffers are sized up front for the highest rate we expect to run at
+const REVERB_MAX_SAMPLE_RATE: f32 = 192_000.0;
+const REVERB_INPUT_GAIN: f32 = 0.015;
+const REVERB_WET_SCALE: f32 = 3.0;
+// Smoothing time of the wet level in seconds, so turning it down doesn't click
+const REVERB_WET_SMOOTHING: f32 = 0.023;
+
+// Lowpass-feedback comb filter
+#[derive(Clone, Debug, PartialEq)]
+struct Comb {
+    buffer: Vec<f32>,
+    len: usize,
+    index: usize,
+    filter_store: f32,
+}
+
+impl Comb {
+    fn process(&mut self, input: f32, feedback: f32, damp: f32) -> f32 {
+        let output = self.buffer[self.index];
+        self.filter_store = output * (1.0 - damp) + self.filter_store * damp;
+        self.buffer[self.index] = input + self.filter_store * feedback;
+        self.index = (self.index + 1) % self.len;
+        output
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Allpass {
+    buffer: Vec<f32>,
+    len: usize,
+    index: usize,
+}
+
+impl Allpass {
+    fn process(&mut self, input: f32) -> f32 {
+        let buffered = self.buffer[self.index];
+        self.buffer[self.index] = input + buffered * 0.5;
+        self.index = (self.index + 1) % self.len;
+        buffered - input
+    }
+}
+
+// Freeverb-style reverb: parallel combs into series allpasses, per channel
+#[derive(Clone, Debug, PartialEq)]
+struct ReverbState {
+    room_size: f32,
+    damping: f32,
+    wet: f32,
+    smoothed_wet: f32,
+    wet_smoothing: f32, // Wet smoothing at the current sample rate
+    sample_rate: f32,
+    combs: [Vec<Comb>; 2],
+    allpasses: [Vec<Allpass>; 2],
+}
+
+impl ReverbState {
+    // Allocates every buffer at its largest size so nothing is allocated on the audio thread
+    fn new() -> Self {
+        let max_len = |tuning: usize| {
+            ((tuning + REVERB_STEREO_SPREAD) as f32 * REVERB_MAX_SAMPLE_RATE / 44_100.0).ceil()
+                as usize
+        };
+        let combs = || {
+            REVERB_COMB_TUNINGS
+                .iter()
+                .map(|&tuning| Comb {
+                    buffer: vec![0.0; max_len(tuning)],
+                    len: 1,
+                    index: 0,
+                    filter_store: 0.0,
+                })
+                .collect()
+        };
+        let allpasses = || {
+            REVERB_ALLPASS_TUNINGS
+                .iter()
+                .map(|&tuning| Allpass {
+                    buffer: vec![0.0; max_len(tuning)],
+                    len: 1,
+                    index: 0,
+                })
+                .collect()
+        };
+        ReverbState {
+            room_size: 0.5,
+            damping: 0.5,
+            wet: 0.0,
+            smoothed_wet: 0.0,
+            wet_smoothing: 1.0,
+            sample_rate: 0.0,
+            combs: [combs(), combs()],
+            allpasses: [allpasses(), allpasses()],
+        }
+    }
+
+    // Scales the delay lengths to the sample rate, called once per audio buffer
+    fn prepare(&mut self, sample_rate: f32) {
+        if sample_rate == self.sample_rate {
+            return;
+        }
+        self.sample_rate = sample_rate;
+        self.wet_smoothing = dsp::smoothing(REVERB_WET_SMOOTHING, sample_rate);
+        let scale = sample_rate.min(REVERB_MAX_SAMPLE_RATE) / 44_100.0;
+        for ch in 0..2 {
+            let spread = ch * REVERB_STEREO_SPREAD;
+            for (comb, tuning) in self.combs[ch].iter_mut().zip(REVERB_COMB_TUNINGS) {
+                comb.len = (((tuning + spread) as f32 * scale) as usize).max(1);
+                comb.index %= comb.len;
+            }
+            for (allpass, tuning) in self.allpasses[ch].iter_mut().zip(REVERB_ALLPASS_TUNINGS) {
+                allpass.len = (((tuning + spread) as f32 * scale) as usize).max(1);
+                allpass.index %= allpass.len;
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        for comb in self.combs.iter_mut().flatten() {
+            comb.buffer.fill(0.0);
+            comb.filter_store = 0.0;
+        }
+        for allpass in self.allpasses.iter_mut().flatten() {
+            allpass.buffer.fill(0.0);
+        }
+    }
+
+    fn process(&mut self, input: [f32; 2]) -> [f32; 2] {
+        // Room size only changes the feedback, so the buffers never need resizing
+        let feedback = 0.7 + 0.28 * self.room_size;
+        let damp = 0.4 * self.damping;
+        self.smoothed_wet += (self.wet - self.smoothed_wet) * self.wet_smoothing;
+        let wet = self.smoothed_wet;
+        let mono = (input[0] + input[1]) * REVERB_INPUT_GAIN;
+
+        let mut output = [0.0; 2];
+        for (ch, out) in output.iter_mut().enumerate() {
+            let mut reverb = 0.0;
+            for comb in self.combs[ch].iter_mut() {
+                reverb += comb.process(mono, feedback, damp);
+            }
+            for allpass in self.allpasses[ch].iter_mut() {
+                reverb = allpass.process(reverb);
+            }
+            *out = input[ch] * (1.0 - wet) + reverb * wet * REVERB_WET_SCALE;
+        }
+        output
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Chorus {
+    rate: f32, // Hz
+    depth: f32,
+    mix: f32,
+    feedback: f32, // Zero for chorus, higher for flanging
+}
+
+const MIN_CHORUS_RATE: f32 = 0.05;
+const MAX_CHORUS_RATE: f32 = 10.0;
+const MAX_CHORUS_FEEDBACK: f32 = 0.9;
+// Centre of the modulated delay and its swing at full depth, in seconds
+const CHORUS_BASE_DELAY: f32 = 0.012;
+const CHORUS_MAX_SWING: f32 = 0.008;
+// Buffer length, with room to spare beyond the longest modulated delay at 192kHz
+const CHORUS_BUFFER_LEN: usize = 8192;
+
+// Short delay line read at a position swept by its own LFO, with the right channel a
+// quarter cycle behind for width
+#[derive(Clone, Debug, PartialEq)]
+struct ChorusState {
+    rate: f32,
+    depth: f32,
+    mix: f32,
+    feedback: f32,
+    sample_rate: f32,
+    phase: f32,
+    buffers: [Vec<f32>; 2],
+    write_index: usize,
+}
+
+impl ChorusState {
+    fn new() -> Self {
+        ChorusState {
+            rate: 0.8,
+            depth: 0.5,
+            mix: 0.5,
+            feedback: 0.0,
+            sample_rate: 44_100.0,
+            phase: 0.0,
+            buffers: [vec![0.0; CHORUS_BUFFER_LEN], vec![0.0; CHORUS_BUFFER_LEN]],
+            write_index: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        for buffer in self.buffers.iter_mut() {
+            buffer.fill(0.0);
+        }
+    }
+
+    // Linearly interpolated read `delay` samples behind the write head. Indices wrap
+    // around the ring, so there's no discontinuity where the buffer loops.
+    fn read(&self, ch: usize, delay: f32) -> f32 {
+        let len = CHORUS_BUFFER_LEN as f32;
+        let position = (self.write_index as f32 - delay).rem_euclid(len);
+        let index = position as usize % CHORUS_BUFFER_LEN;
+        let next = (index + 1) % CHORUS_BUFFER_LEN;
+        let frac = position.fract();
+        let buffer = &self.buffers[ch];
+        buffer[index] + (buffer[next] - buffer[index]) * frac
+    }
+
+    fn process(&mut self, input: [f32; 2]) -> [f32; 2] {
+        let mut output = [0.0; 2];
+        for (ch, out) in output.iter_mut().enumerate() {
+            let lfo = (std::f32::consts::TAU * (self.phase + 0.25 * ch as f32)).sin();
+            let seconds = CHORUS_BASE_DELAY + CHORUS_MAX_SWING * self.depth * lfo;
+            // Keep the read head at least one sample behind the write head and inside
+            // the buffer
+            let delay = (seconds * self.sample_rate).clamp(1.0, CHORUS_BUFFER_LEN as f32 - 2.0);
+            let delayed = self.read(ch, delay);
+            self.buffers[ch][self.write_index] = input[ch] + delayed * self.feedback;
+            *out = input[ch] * (1.0 - self.mix) + delayed * self.mix;
+        }
+        self.write_index = (self.write_index + 1) % CHORUS_BUFFER_LEN;
+        self.phase = (self.phase + self.rate / self.sample_rate).fract();
+        output
+    }
+}
+
+// Blends the main chain so far with the branch row
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Mixer {
+    gain_a: f32,
+    gain_b: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Sequencer {
+    sequence: Vec<u8>, // MIDI note numbers
+    gates: Vec<bool>,  // Parallel to `sequence`; a closed gate is a rest
+    #[serde(default)]
+    probabilities: Vec<f32>, // Parallel to `sequence`; chance each step plays, missing is 1.0
+    #[serde(default)]
+    ratchets: Vec<u8>, // Parallel to `sequence`; repeats within the step, missing is 1
+    #[serde(default = "default_clock_div")]
+    clock_div: f32, // Steps per master beat
+    #[serde(skip)]
+    clock: f32, // Progress through the current step, 0..1
+    #[serde(skip)]
+    ratchet: Option<Ratchet>,
+    step: usize,
+    root_hz: f32, // Tuning of A4
+    #[serde(default)]
+    scale: Scale,
+    #[serde(default)]
+    target: SeqTarget,
+}
+
+// What a sequencer's steps drive. Pitch plays notes; the others hold a destination's value
+// from step to step.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+enum SeqTarget {
+    #[default]
+    Pitch,
+    Cutoff,
+    DelayMix,
+}
+
+impl SeqTarget {
+    fn next(self) -> Self {
+        match self {
+            SeqTarget::Pitch => SeqTarget::Cutoff,
+            SeqTarget::Cutoff => SeqTarget::DelayMix,
+            SeqTarget::DelayMix => SeqTarget::Pitch,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            SeqTarget::Pitch => "Pch",
+            SeqTarget::Cutoff => "Cut",
+            SeqTarget::DelayMix => "Dly",
+        }
+    }
+
+    // A step's note in the destination's units: cutoff tracks the note's pitch, and the
+    // delay mix spreads the MIDI range over 0..1
+    fn value(self, note: u8, root_hz: f32) -> f32 {
+        match self {
+            SeqTarget::Pitch => note_to_hz(note, root_hz as f64) as f32,
+            SeqTarget::Cutoff => (note_to_hz(note, root_hz as f64) as f32).clamp(20.0, 20000.0),
+            SeqTarget::DelayMix => note as f32 / 127.0,
+        }
+    }
+}
+
+// Scales rooted on C, which step edits snap to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+enum Scale {
+    #[default]
+    Chromatic,
+    Major,
+    Minor,
+    Pentatonic,
+}
+
+impl Scale {
+    fn next(self) -> Self {
+        match self {
+            Scale::Chromatic => Scale::Major,
+            Scale::Major => Scale::Minor,
+            Scale::Minor => Scale::Pentatonic,
+            Scale::Pentatonic => Scale::Chromatic,
+        }
+    }
+
+    // Semitones above C that belong to the scale
+    fn degrees(self) -> &'static [u8] {
+        match self {
+            Scale::Chromatic => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+            Scale::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Scale::Minor => &[0, 2, 3, 5, 7, 8, 10],
+            Scale::Pentatonic => &[0, 2, 4, 7, 9],
+        }
+    }
+
+    fn contains(self, note: u8) -> bool {
+        self.degrees().contains(&(note % 12))
+    }
+
+    // Nearest in-scale note, preferring the lower one on a tie
+    fn quantize(self, note: u8) -> u8 {
+        (0..12u8)
+            .flat_map(|distance| [note.checked_sub(distance), note.checked_add(distance)])
+            .flatten()
+            .find(|&candidate| candidate <= 127 && self.contains(candidate))
+            .unwrap_or(note)
+    }
+
+    // Moves `steps` scale degrees up or down from `note`
+    fn step(self, note: u8, steps: i32) -> u8 {
+        let mut note = self.quantize(note);
+        for _ in 0..steps.unsigned_abs() {
+            let next = (1..12u8)
+                .filter_map(|distance| {
+                    if steps > 0 {
+                        note.checked_add(distance).filter(|&n| n <= 127)
+                    } else {
+                        note.checked_sub(distance)
+                    }
+                })
+                .find(|&candidate| self.contains(candidate));
+            match next {
+                Some(next) => note = next,
+                None => break,
+            }
+        }
+        note
+    }
+}
+
+fn default_clock_div() -> f32 {
+    1.0
+}
+
+impl Sequencer {
+    // Moves the step clock on by one frame, returning whether a new step starts. At the
+    // master rate the sequencer follows the master beat exactly, swing included; other
+    // rates run freely and are pulled back into phase on every bar.
+    fn advance_clock(
+        &mut self,
+        beat_crossed: bool,
+        bar_start: bool,
+        beat_phase: f32,
+        beat_delta: f32,
+    ) -> bool {
+        if self.clock_div == 1.0 {
+            self.clock = beat_phase;
+            return beat_crossed;
+        }
+        if bar_start {
+            self.clock = 0.0;
+            return true;
+        }
+        self.clock += beat_delta * self.clock_div;
+        if self.clock >= 1.0 {
+            self.clock = self.clock.fract();
+            return true;
+        }
+        false
+    }
+
+    // Returns the current step's value for the target, pitch in Hz for notes, and moves
+    // to the next step
+    fn next_value(&mut self) -> f32 {
+        let note = self.scale.quantize(self.sequence[self.step]);
+        self.step = (self.step + 1) % self.sequence.len();
+        self.target.value(note, self.root_hz)
+    }
+
+    // Advances one step, returning the step's value or `None` for a rest. The step
+    // index moves on either way so the rhythm stays intact.
+    fn next_step(&mut self, rng: &mut impl Rng) -> Option<f32> {
+        let gate = self.gates.get(self.step).copied().unwrap_or(true);
+        let probability = self.probabilities.get(self.step).copied().unwrap_or(1.0);
+        // Always roll so the random sequence doesn't depend on which steps are gated
+        let hit = rng.gen::<f32>() < probability;
+        let value = self.next_value();
+        (gate && hit).then_some(value)
+    }
+
+    // Number of evenly spaced triggers the current step plays
+    fn ratchet_count(&self) -> u8 {
+        self.ratchets
+            .get(self.step)
+            .copied()
+            .unwrap_or(1)
+            .clamp(1, MAX_RATCHETS)
+    }
+
+    // Appends a step repeating the last value
+    fn push_step(&mut self) {
+        if self.sequence.len() < MAX_SEQUENCE_STEPS {
+            let last = self.sequence.last().copied().unwrap_or(69);
+            self.sequence.push(last);
+            self.gates.push(true);
+            self.probabilities.resize(self.sequence.len(), 1.0);
+            self.ratchets.resize(self.sequence.len(), 1);
+        }
+    }
+
+    // Switching scale snaps the existing steps into the new one
+    fn set_scale(&mut self, scale: Scale) {
+        self.scale = scale;
+        for note in self.sequence.iter_mut() {
+            *note = scale.quantize(*note);
+        }
+    }
+
+    // Fills every step with a random in-scale note from the two octaves above C3, and
+    // rerolls the gates and any chances. The length stays as it is.
+    fn randomize(&mut self, rng: &mut impl Rng) {
+        let degrees = self.scale.degrees();
+        for note in self.sequence.iter_mut() {
+            let octave = rng.gen_range(0..RANDOM_OCTAVES);
+            *note = RANDOM_LOWEST_NOTE + 12 * octave + degrees[rng.gen_range(0..degrees.len())];
+        }
+        self.gates = (0..self.sequence.len())
+            .map(|_| rng.gen::<f32>() < RANDOM_GATE_CHANCE)
+            .collect();
+        for probability in self.probabilities.iter_mut() {
+            *probability = if rng.gen::<f32>() < RANDOM_GATE_CHANCE {
+                1.0
+            } else {
+                0.5
+            };
+        }
+        if self.step >= self.sequence.len() {
+            self.step = 0;
+        }
+    }
+
+    // A loaded sequencer needs a step to play, a gate, chance and ratchet count for each
+    // step, and a step index within its sequence
+    fn repair(&mut self) {
+        if self.sequence.is_empty() {
+            self.push_step();
+        }
+        let len = self.sequence.len();
+        self.gates.resize(len, true);
+        self.probabilities.resize(len, 1.0);
+        self.ratchets.resize(len, 1);
+        if self.step >= self.sequence.len() {
+            self.step = 0;
+        }
+    }
+
+    // Removes the last step, always keeping at least one
+    fn pop_step(&mut self) {
+        if self.sequence.len() > 1 {
+            self.sequence.pop();
+            self.gates.truncate(self.sequence.len());
+            self.probabilities.truncate(self.sequence.len());
+            self.ratchets.truncate(self.sequence.len());
+            if self.step >= self.sequence.len() {
+                self.step = 0;
+            }
+        }
+    }
+}
+
+const MAX_SEQUENCE_STEPS: usize = 16;
+// Range and density of randomized patterns
+const RANDOM_LOWEST_NOTE: u8 = 48;
+const RANDOM_OCTAVES: u8 = 2;
+const RANDOM_GATE_CHANCE: f32 = 0.75;
+// Beats in a bar by default. The metronome accents the first, and per-sequencer clocks
+// realign with the master clock on it.
+const BEATS_PER_BAR: usize = 4;
+const MAX_RATCHETS: u8 = 4;
+
+// Repeats still to play within the current sequencer step
+#[derive(Clone, Debug, PartialEq)]
+struct Ratchet {
+    hz: f64,
+    count: u8,
+    fired: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum ArpMode {
+    Up,
+    Down,
+    UpDown,
+    Random,
+}
+
+impl ArpMode {
+    fn next(self) -> Self {
+        match self {
+            ArpMode::Up => ArpMode::Down,
+            ArpMode::Down => ArpMode::UpDown,
+            ArpMode::UpDown => ArpMode::Random,
+            ArpMode::Random => ArpMode::Up,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            ArpMode::Up => "Up",
+            ArpMode::Down => "Dn",
+            ArpMode::UpDown => "UD",
+            ArpMode::Random => "Rnd",
+        }
+    }
+}
+
+// Plays the held notes one at a time on the beat clock
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Arpeggiator {
+    mode: ArpMode,
+    octaves: u8, // Octaves the held notes are repeated over
+    rate: BeatDivision,
+    #[serde(skip)]
+    clock: f32, // Beats since the last note
+    #[serde(skip)]
+    step: usize,
+}
+
+const MAX_ARP_OCTAVES: u8 = 4;
+
+impl Arpeggiator {
+    // Returns the note to play this frame, if a step starts. The pool is rebuilt from the
+    // held notes every step, so notes pressed or released mid-pattern join or leave it at
+    // the next step without resetting the clock.
+    fn next_note(&mut self, held: &[u8], beat_delta: f32, rng: &mut StdRng) -> Option<u8> {
+        if held.is_empty() {
+            self.clock = 0.0;
+            self.step = 0;
+            return None;
+        }
+        let first = self.step == 0 && self.clock == 0.0;
+        self.clock += beat_delta;
+        if !first && self.clock < self.rate.beats() {
+            return None;
+        }
+        if !first {
+            self.clock -= self.rate.beats();
+        }
+
+        let mut sorted = held.to_vec();
+        sorted.sort_unstable();
+        let pool: Vec<u8> = (0..self.octaves.clamp(1, MAX_ARP_OCTAVES))
+            .flat_map(|octave| {
+                sorted
+                    .iter()
+                    .filter_map(move |&n| n.checked_add(12 * octave))
+            })
+            .filter(|&n| n <= 127)
+            .collect();
+        let len = pool.len();
+        let index = match self.mode {
+            ArpMode::Up => self.step % len,
+            ArpMode::Down => len - 1 - self.step % len,
+            ArpMode::UpDown if len > 1 => {
+                // Turns around without repeating the top and bottom notes
+                let i = self.step % (2 * len - 2);
+                if i < len {
+                    i
+                } else {
+                    2 * len - 2 - i
+                }
+            }
+            ArpMode::UpDown => 0,
+            ArpMode::Random => rng.gen_range(0..len),
+        };
+        self.step += 1;
+        Some(pool[index])
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Envelope {
+    attack: f32,
+    decay: f32,
+    sustain: f32,
+    release: f32,
+    #[serde(default)]
+    curve: EnvCurve,
+    // How much note velocity scales the level: 0 ignores it, 1 follows it fully
+    #[serde(default = "default_velocity_amount")]
+    velocity_amount: f32,
+}
+
+fn default_velocity_amount() -> f32 {
+    1.0
+}
+
+const MAX_VOICES: usize = 8;
+// Time taken to silence a stolen voice before its new note starts, in seconds
+const VOICE_STEAL_TIME: f32 = 0.003;
+// Smoothing time of the headroom gain in seconds
+const VOICE_GAIN_SMOOTHING: f32 = 0.023;
+// Voices fade out over this long when the transport stops, rather than cutting off
+const STOP_FADE_TIME: f32 = 0.02;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct VoiceStart {
+    note: Option<u8>, // Key that releases the note, or `None` for one-shots
+    hz: f64,
+    from_hz: f64, // Where the pitch glides from
+    velocity: f32,
+    hold: bool,
+    seed: u32, // Scatters the unison phases
+}
+
+// One note of the polyphonic pool, with its own pitch, envelope and oscillator phases
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Voice {
+    note: Option<u8>,
+    hz: f64,
+    target_hz: f64,
+    phases: [f64; MAX_OSCILLATORS],
+    increment: f64,                     // Cycles per sample at the current pitch
+    mod_phases: [f64; MAX_OSCILLATORS], // FM modulator phase for each oscillator
+    cycles: [u8; MAX_OSCILLATORS],      // Completed cycles, which the sub-oscillator follows
+    unison_phases: [[f64; MAX_UNISON]; MAX_OSCILLATORS],
+    envelope: dsp::Envelope,
+    age: u64,
+    fade: f32,                   // Drops to silence while the voice is being stolen
+    pending: Option<VoiceStart>, // Note waiting for the steal fade to finish
+    stopping: bool,              // Fading out because the transport stopped
+}
+
+impl Voice {
+    fn new() -> Self {
+        Voice {
+            note: None,
+            hz: 440.0,
+            target_hz: 440.0,
+            phases: [0.0; MAX_OSCILLATORS],
+            increment: 0.0,
+            mod_phases: [0.0; MAX_OSCILLATORS],
+            cycles: [0; MAX_OSCILLATORS],
+            unison_phases: [[0.0; MAX_UNISON]; MAX_OSCILLATORS],
+            envelope: dsp::Envelope::new(),
+            age: 0,
+            fade: 1.0,
+            pending: None,
+            stopping: false,
+        }
+    }
+
+    fn is_free(&self) -> bool {
+        self.envelope.stage == EnvStage::Idle && self.pending.is_none()
+    }
+
+    fn start(&mut self, start: VoiceStart) {
+        self.note = start.note;
+        self.hz = start.from_hz;
+        self.target_hz = start.hz;
+        self.envelope.gate_on(start.hold, start.velocity);
+        // Unison copies start at random phases so the onset isn't comb filtered
+        let mut seed = start.seed;
+        for phase in self.unison_phases.iter_mut().flatten() {
+            *phase = (white_noise(&mut seed) as f64 + 1.0) * 0.5;
+        }
+    }
+
+    // Advances the steal fade, glide, phases and envelope by one sample, returning the
+    // envelope level
+    fn next(
+        &mut self,
+        dt: f32,
+        glide: f64,
+        phase_scale: f64,
+        oscillators: &[OscillatorState],
+    ) -> f32 {
+        if let Some(start) = self.pending {
+            self.fade -= dt / VOICE_STEAL_TIME;
+            if self.fade <= 0.0 {
+                self.fade = 1.0;
+                self.pending = None;
+                self.stopping = false;
+                self.envelope.level = 0.0;
+                self.start(start);
+            }
+        } else if self.stopping {
+            self.fade -= dt / STOP_FADE_TIME;
+            if self.fade <= 0.0 {
+                self.fade = 1.0;
+                self.stopping = false;
+                self.note = None;
+                self.envelope.level = 0.0;
+                self.envelope.stage = EnvStage::Idle;
+            }
+        }
+        self.hz = self.target_hz + (self.hz - self.target_hz) * glide;
+        let step = self.hz * phase_scale;
+        self.increment = step;
+        for (index, osc) in oscillators.iter().enumerate() {
+            let next = self.phases[index] + step;
+            if next >= 1.0 {
+                self.cycles[index] = self.cycles[index].wrapping_add(1);
+            }
+            self.phases[index] = next.fract();
+            self.mod_phases[index] = (self.mod_phases[index] + step * osc.fm_ratio()).fract();
+            let copies = self.unison_phases[index].iter_mut().zip(osc.detune_ratios);
+            for (phase, ratio) in copies.take(osc.unison) {
+                *phase = (*phase + step * ratio).fract();
+            }
+        }
+        self.envelope.next(dt) * self.fade
+    }
+}
+
+impl Audio {
+    // Gives the note a voice: the one already playing the same key, a free one, or
+    // failing that the oldest, which fades out quickly before it is reused
+    fn note_on(&mut self, note: Option<u8>, hz: f64, velocity: f32, hold: bool) {
+        // Latched, each note holds until the next one, and a key already holding a note
+        // lets it go instead of playing again
+        let hold = if self.latch {
+            let latched = |v: &Voice| note.is_some() && v.note == note && v.envelope.is_gated();
+            if self.voices.iter().any(latched) {
+                self.release_all();
+                return;
+            }
+            self.release_all();
+            true
+        } else {
+            hold
+        };
+        let from_hz = if self.glide_time > 0.0 {
+            self.last_hz
+        } else {
+            hz
+        };
+        self.last_hz = hz;
+        self.notes_started += 1;
+        white_noise(&mut self.phase_seed);
+        self.sampler.trigger();
+        let start = VoiceStart {
+            note,
+            hz,
+            from_hz,
+            velocity,
+            hold,
+            seed: self.phase_seed,
+        };
+
+        let same_key = |voice: &Voice| note.is_some() && voice.note == note;
+        let index = self
+            .voices
+            .iter()
+            .position(same_key)
+            .or_else(|| self.voices.iter().position(Voice::is_free))
+            .or_else(|| (0..MAX_VOICES).min_by_key(|&i| self.voices[i].age))
+            .unwrap();
+        let voice = &mut self.voices[index];
+        voice.age = self.notes_started;
+        // A voice fading out for a stop finishes its fade before the new note starts
+        if (voice.is_free() || same_key(voice)) && !voice.stopping {
+            voice.pending = None;
+            voice.start(start);
+        } else {
+            voice.note = note;
+            voice.pending = Some(start);
+        }
+    }
+
+    fn note_off(&mut self, note: u8) {
+        if self.latch {
+            return;
+        }
+        for voice in self.voices.iter_mut().filter(|v| v.note == Some(note)) {
+            match &mut voice.pending {
+                Some(start) => start.hold = false,
+                None => voice.envelope.gate_off(),
+            }
+        }
+    }
+
+    fn play_event(&mut self, event: NoteEvent) {
+        match event {
+            NoteEvent::On { hz, velocity } => self.note_on(None, hz, velocity, false),
+            NoteEvent::Retrigger => self.note_on(None, self.free_hz, 1.0, false),
+            NoteEvent::Click { accent } => self.click.start(accent),
+        }
+    }
+
+    // Lets every held note go into its release
+    fn release_all(&mut self) {
+        for voice in self.voices.iter_mut() {
+            match &mut voice.pending {
+                Some(start) => start.hold = false,
+                None if voice.envelope.is_gated() => voice.envelope.gate_off(),
+                None => {}
+            }
+        }
+    }
+
+    fn set_latch(&mut self, latch: bool) {
+        self.latch = latch;
+        if !latch {
+            self.release_all();
+        }
+    }
+
+    // Fades out every sounding voice
+    fn stop_voices(&mut self) {
+        // Notes already scheduled would otherwise start after the stop
+        while self.events.pop().is_ok() {}
+        for voice in self.voices.iter_mut().filter(|v| !v.is_free()) {
+            voice.pending = None;
+            voice.stopping = true;
+        }
+    }
+
+    // Empties every effect's memory, so nothing from before rings on into what comes next
+    fn clear_tails(&mut self) {
+        self.stop_voices();
+        self.delay.clear();
+        self.reverb.clear();
+        self.chorus.clear();
+        self.filter.reset();
+        self.eq.reset();
+        self.compressor.envelope = 0.0;
+        self.sampler.playing = false;
+    }
+
+    // Moves the free-running pitch, along with any one-shot notes still sounding
+    fn drift(&mut self, hz: f64) {
+        self.free_hz += hz;
+        for voice in self.voices.iter_mut().filter(|v| v.note.is_none()) {
+            voice.target_hz += hz;
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Delay {
+    delay_time: f32,
+    feedback: f32,
+    wet: f32,
+    sync: Option<BeatDivision>,
+    ping_pong: bool,
+    #[serde(default)]
+    frozen: bool, // Loops what's in the buffer forever, ignoring new input
+}
+
+// Note lengths for tempo-synced timing
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum BeatDivision {
+    Half,
+    Quarter,
+    DottedEighth,
+    Eighth,
+    EighthTriplet,
+    Sixteenth,
+}
+
+impl BeatDivision {
+    // Length in beats (quarter notes)
+    fn beats(self) -> f32 {
+        match self {
+            BeatDivision::Half => 2.0,
+            BeatDivision::Quarter => 1.0,
+            BeatDivision::DottedEighth => 0.75,
+            BeatDivision::Eighth => 0.5,
+            BeatDivision::EighthTriplet => 1.0 / 3.0,
+            BeatDivision::Sixteenth => 0.25,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            BeatDivision::Half => "1/2",
+            BeatDivision::Quarter => "1/4",
+            BeatDivision::DottedEighth => "1/8.",
+            BeatDivision::Eighth => "1/8",
+            BeatDivision::EighthTriplet => "1/8T",
+            BeatDivision::Sixteenth => "1/16",
+        }
+    }
+
+    // Steps through the divisions, with `None` (free-running) at the end of the cycle
+    fn cycle(division: Option<BeatDivision>) -> Option<BeatDivision> {
+        match division {
+            None => Some(BeatDivision::Half),
+            Some(BeatDivision::Half) => Some(BeatDivision::Quarter),
+            Some(BeatDivision::Quarter) => Some(BeatDivision::DottedEighth),
+            Some(BeatDivision::DottedEighth) => Some(BeatDivision::Eighth),
+            Some(BeatDivision::Eighth) => Some(BeatDivision::EighthTriplet),
+            Some(BeatDivision::EighthTriplet) => Some(BeatDivision::Sixteenth),
+            Some(BeatDivision::Sixteenth) => None,
+        }
+    }
+}
+
+// Longest delay the ring buffer has room for, in seconds
+const MAX_DELAY_TIME: f32 = 2.0;
+// Crossfade into and out of a frozen delay loop, in seconds
+const FREEZE_FADE_TIME: f32 = 0.005;
+// Crossfade from the old read position to the new one when the delay time changes, in seconds
+const DELAY_FADE_TIME: f32 = 0.02;
+
+// Delay line state, owned by the audio thread. Each channel has its own ring buffer,
+// which ping-pong mode cross-feeds.
+struct DelayState {
+    delay_time: f32,
+    feedback: f32,
+    wet: f32,
+    ping_pong: bool,
+    frozen: bool,
+    freeze: f32,      // Crossfade into the frozen loop, 0 to 1
+    freeze_step: f32, // Per-sample change of `freeze`
+    buffers: [Vec<f32>; 2],
+    write_index: usize,
+    len: usize,     // Delay in samples: how far behind the write index the line is read
+    old_len: usize, // Where the line was read before `len` last changed
+    shift: f32,     // Crossfade from the `old_len` read to the `len` one, 0 to 1
+    shift_step: f32,
+}
+
+impl DelayState {
+    fn new() -> Self {
+        DelayState {
+            delay_time: 0.5,
+            feedback: 0.5,
+            wet: 0.5,
+            ping_pong: false,
+            frozen: false,
+            freeze: 0.0,
+            freeze_step: 0.0,
+            buffers: [vec![], vec![]],
+            write_index: 0,
+            len: 0,
+            old_len: 0,
+            shift: 1.0,
+            shift_step: 0.0,
+        }
+    }
+
+    // Sets the delay to `delay_time * sample_rate` samples, called once per audio buffer.
+    // A new time only moves where the line is read, so tempo changes never touch the buffers.
+    // The read crossfades to its new position, and a change that arrives mid-fade waits for
+    // the next buffer after it, so a synced delay never jumps when the tempo moves.
+    fn prepare(&mut self, sample_rate: f32) {
+        self.freeze_step = 1.0 / (FREEZE_FADE_TIME * sample_rate);
+        self.shift_step = 1.0 / (DELAY_FADE_TIME * sample_rate);
+        let len = (self.delay_time.clamp(0.001, MAX_DELAY_TIME) * sample_rate).round() as usize;
+        let len = len.clamp(1, self.buffers[0].len().max(2) - 1);
+        if self.len == 0 {
+            // Nothing has been read yet, so there's nothing to fade from
+            (self.len, self.old_len) = (len, len);
+        } else if len != self.len && self.shift >= 1.0 {
+            (self.old_len, self.len, self.shift) = (self.len, len, 0.0);
+        }
+    }
+
+    fn clear(&mut self) {
+        for buffer in self.buffers.iter_mut() {
+            buffer.fill(0.0);
+        }
+        self.frozen = false;
+        self.freeze = 0.0;
+    }
+
+    fn process(&mut self, input: [f32; 2]) -> [f32; 2] {
+        let size = self.buffers[0].len();
+        // Nothing to delay with until the stream's buffers arrive
+        if size == 0 {
+            return input;
+        }
+        let w = self.write_index;
+        let r = (w + size - self.len) % size;
+        let old = (w + size - self.old_len) % size;
+        let delayed = [0, 1].map(|channel| {
+            let buffer = &self.buffers[channel];
+            buffer[old] + (buffer[r] - buffer[old]) * self.shift
+        });
+        self.shift = dsp::ramp(self.shift, true, self.shift_step);
+        let written = if self.ping_pong {
+            // Input enters on the left and each echo crosses to the other side
+            let mono = (input[0] + input[1]) * 0.5;
+            [
+                mono + delayed[1] * self.feedback,
+                delayed[0] * self.feedback,
+            ]
+        } else {
+            [
+                input[0] + delayed[0] * self.feedback,
+                input[1] + delayed[1] * self.feedback,
+            ]
+        };
+        // Frozen, each sample is written back unchanged so the buffer repeats at unity
+        // gain. Fading between the two avoids a seam where the loop starts or ends.
+        let target = if self.frozen { 1.0 } else { 0.0 };
+        self.freeze = if self.freeze < target {
+            (self.freeze + self.freeze_step).min(target)
+        } else {
+            (self.freeze - self.freeze_step).max(target)
+        };
+        for channel in 0..2 {
+            self.buffers[channel][w] =
+                written[channel] + (delayed[channel] - written[channel]) * self.freeze;
+        }
+        self.write_index = (w + 1) % size;
+        [
+            input[0] * (1.0 - self.wet) + delayed[0] * self.wet,
+            input[1] * (1.0 - self.wet) + delayed[1] * self.wet,
+        ]
+    }
+}
+
+// Ring buffers long enough for the longest delay at `sample_rate`. They're made before
+// a stream starts and handed over whole, so the audio thread never allocates.
+fn delay_buffers(sample_rate: f32) -> [Vec<f32>; 2] {
+    let size = (MAX_DELAY_TIME * sample_rate).round() as usize + 1;
+    [vec![0.0; size], vec![0.0; size]]
+}
+
+fn send_delay_buffers(stream: &audio::Stream<Audio>) {
+    let buffers = delay_buffers(stream.cpal_config().sample_rate.0 as f32);
+    stream
+        .send(move |audio| audio.delay.buffers = buffers)
+        .unwrap();
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum CardClass {
+    Oscillator(Oscillator),
+    Sequencer(Sequencer),
+    Envelope(Envelope),
+    Delay(Delay),
+    Noise(Noise),
+    Sampler(Sampler),
+    Filter(Filter),
+    Lfo(Lfo),
+    Mixer(Mixer),
+    Bitcrush(Bitcrush),
+    Reverb(Reverb),
+    Chorus(Chorus),
+    Arpeggiator(Arpeggiator),
+    Eq(Equalizer),
+    Compressor(Compressor),
+    Vca(Vca),
+    // Add more variants here as needed
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Card {
+    x: f32,
+    x_last: f32,
+    x_targ: f32,
+    y: f32,
+    y_last: f32,
+    y_targ: f32,
+    w: f32,
+    h: f32,
+    #[serde(skip)]
+    dragging: bool,
+    rotation: f32,
+    scale: f32,
+    start_time: f32,
+    #[serde(default)]
+    bypassed: bool, // Stays in the chain but is skipped by the audio
+    class: CardClass,
+}
+
+const CARD_WIDTH: f32 = 100.0;
+const CARD_HEIGHT: f32 = 140.0;
+
+impl Card {
+    fn default(class: CardClass) -> Self {
+        Card {
+            x: 0.0,
+            x_last: 0.0,
+            x_targ: 0.0,
+            y: 0.0,
+            y_last: 0.0,
+            y_targ: 0.0,
+            w: CARD_WIDTH,
+            h: CARD_HEIGHT,
+            dragging: false,
+            rotation: 0.0,
+            scale: 1.0,
+            start_time: 0.0,
+            bypassed: false,
+            class,
+        }
+    }
+
+    fn new(x: f32, y: f32, class: CardClass) -> Self {
+        let mut card = Card::default(class);
+        card.x = x;
+        card.x_last = x;
+        card.x_targ = x;
+        card.y = y;
+        card.y_last = y;
+        card.y_targ = y;
+        card
+    }
+}
+
+// One fresh card of each type, in the order the palette lists them
+fn palette_classes() -> Vec<CardClass> {
+    vec![
+        CardClass::Oscillator(Oscillator {
+            waveform: Waveform::Sine,
+            pulse_width: 0.5,
+            modulator: None,
+            sub_level: 0.0,
+            sub_octaves: 1,
+            unison: 1,
+            detune_cents: 10.0,
+            pan: 0.0,
+        }),
+        CardClass::Noise(Noise {
+            color: NoiseColor::White,
+            amplitude: 0.5,
+        }),
+        CardClass::Sampler(Sampler {
+            path: SAMPLE_PATH.to_string(),
+            loop_mode: false,
+            pitch: 1.0,
+        }),
+        CardClass::Sequencer(Sequencer {
+            sequence: vec![60; 4],
+            gates: vec![true; 4],
+            probabilities: vec![1.0; 4],
+            ratchets: vec![1; 4],
+            clock_div: 1.0,
+            clock: 0.0,
+            ratchet: None,
+            step: 0,
+            root_hz: 440.0,
+            scale: Scale::Chromatic,
+            target: SeqTarget::Pitch,
+        }),
+        CardClass::Arpeggiator(Arpeggiator {
+            mode: ArpMode::Up,
+            octaves: 1,
+            rate: BeatDivision::Eighth,
+            clock: 0.0,
+            step: 0,
+        }),
+        CardClass::Envelope(Envelope {
+            attack: 0.1,
+            decay: 1.0,
+            sustain: 0.4,
+            release: 0.5,
+            curve: EnvCurve::Linear,
+            velocity_amount: 1.0,
+        }),
+        CardClass::Lfo(Lfo {
+            rate: 5.0,
+            depth: 0.02,
+            shape: Waveform::Sine,
+            target: ModDest::Pitch,
+        }),
+        CardClass::Filter(Filter {
+            cutoff: 1200.0,
+            resonance: 0.3,
+            mode: FilterMode::LowPass,
+        }),
+        CardClass::Delay(Delay {
+            delay_time: 0.5,
+            feedback: 0.5,
+            wet: 0.5,
+            sync: None,
+            ping_pong: false,
+            frozen: false,
+        }),
+        CardClass::Reverb(Reverb {
+            room_size: 0.6,
+            damping: 0.5,
+            wet: 0.3,
+        }),
+        CardClass::Chorus(Chorus {
+            rate: 0.8,
+            depth: 0.5,
+            mix: 0.5,
+            feedback: 0.0,
+        }),
+        CardClass::Eq(Equalizer {
+            low: 0.0,
+            mid: 0.0,
+            high: 0.0,
+            mid_freq: 1000.0,
+        }),
+        CardClass::Compressor(Compressor {
+            threshold: -18.0,
+            ratio: 4.0,
+            attack: 0.01,
+            release: 0.2,
+            makeup: 0.0,
+        }),
+        CardClass::Bitcrush(Bitcrush {
+            bits: 8,
+            downsample: 4,
+        }),
+        CardClass::Vca(Vca { gain: 1.0 }),
+        CardClass::Mixer(Mixer {
+            gain_a: 1.0,
+            gain_b: 1.0,
+        }),
+    ]
+}
+
+// A card field the inspector can edit; whole-number fields are rounded after each nudge
+enum ParamValue<'a> {
+    Float(&'a mut f32),
+    Byte(&'a mut u8),
+    Count(&'a mut u32),
+    Note(&'a mut u8, Scale), // MIDI note, shown by name and stepped through the scale
+}
+
+// An editable number on a card, as listed by the inspector
+struct Param<'a> {
+    name: String,
+    value: ParamValue<'a>,
+    min: f32,
+    max: f32,
+    step: f32,
+    log: bool, // Steps multiply by `1 + step` rather than adding it
+}
+
+impl Param<'_> {
+    fn get(&self) -> f32 {
+        match &self.value {
+            ParamValue::Float(value) => **value,
+            ParamValue::Byte(value) | ParamValue::Note(value, _) => **value as f32,
+            ParamValue::Count(value) => **value as f32,
+        }
+    }
+
+    fn nudge(&mut self, steps: f32) {
+        if let ParamValue::Note(note, scale) = &mut self.value {
+            **note = scale.step(**note, steps.round() as i32);
+            return;
+        }
+        let value = if self.log {
+            self.get() * (1.0 + self.step).powf(steps)
+        } else {
+            self.get() + self.step * steps
+        };
+        self.set(value);
+    }
+
+    // Sets the value directly, rounding whole-number fields
+    fn set(&mut self, value: f32) {
+        let value = value.clamp(self.min, self.max);
+        match &mut self.value {
+            ParamValue::Float(field) => **field = value,
+            ParamValue::Byte(field) | ParamValue::Note(field, _) => **field = value.round() as u8,
+            ParamValue::Count(field) => **field = value.round() as u32,
+        }
+    }
+
+    fn display(&self) -> String {
+        match self.value {
+            ParamValue::Float(_) => format!("{}: {:.2}", self.name, self.get()),
+            ParamValue::Note(ref note, _) => format!("{}: {}", self.name, note_name(**note)),
+            _ => format!("{}: {}", self.name, self.get()),
+        }
+    }
+}
+
+fn param<'a>(
+    name: impl Into<String>,
+    value: &'a mut f32,
+    min: f32,
+    max: f32,
+    step: f32,
+) -> Param<'a> {
+    Param {
+        name: name.into(),
+        value: ParamValue::Float(value),
+        min,
+        max,
+        step,
+        log: false,
+    }
+}
+
+// Frequencies and rates are edited in proportional steps
+fn log_param<'a>(name: impl Into<String>, value: &'a mut f32, min: f32, max: f32) -> Param<'a> {
+    Param {
+        name: name.into(),
+        value: ParamValue::Float(value),
+        min,
+        max,
+        step: 0.05,
+        log: true,
+    }
+}
+
+fn whole_param<'a>(
+    name: impl Into<String>,
+    value: ParamValue<'a>,
+    min: f32,
+    max: f32,
+) -> Param<'a> {
+    Param {
+        name: name.into(),
+        value,
+        min,
+        max,
+        step: 1.0,
+        log: false,
+    }
+}
+
+fn card_params(class: &mut CardClass) -> Vec<Param<'_>> {
+    match class {
+        CardClass::Oscillator(osc) => {
+            let mut params = vec![param(
+                "width",
+                &mut osc.pulse_width,
+                MIN_PULSE_WIDTH,
+                MAX_PULSE_WIDTH,
+                0.05,
+            )];
+            params.push(param("sub", &mut osc.sub_level, 0.0, 1.0, 0.05));
+            params.push(whole_param(
+                "sub oct",
+                ParamValue::Byte(&mut osc.sub_octaves),
+                1.0,
+                MAX_SUB_OCTAVES as f32,
+            ));
+            params.push(whole_param(
+                "unison",
+                ParamValue::Byte(&mut osc.unison),
+                1.0,
+                MAX_UNISON as f32,
+            ));
+            params.push(param(
+                "detune",
+                &mut osc.detune_cents,
+                0.0,
+                MAX_DETUNE_CENTS,
+                1.0,
+            ));
+            params.push(param("pan", &mut osc.pan, -1.0, 1.0, 0.05));
+            if let Some(fm) = osc.modulator.as_deref_mut() {
+                params.push(log_param(
+                    "fm ratio",
+                    &mut fm.ratio,
+                    MIN_FM_RATIO,
+                    MAX_FM_RATIO,
+                ));
+                params.push(param("fm index", &mut fm.index, 0.0, MAX_FM_INDEX, 0.25));
+            }
+            params
+        }
+        CardClass::Sequencer(seq) => {
+            let scale = seq.scale;
+            let mut params = vec![];
+            let steps = seq
+                .sequence
+                .iter_mut()
+                .zip(seq.probabilities.iter_mut())
+                .zip(seq.ratchets.iter_mut());
+            for (i, ((note, chance), ratchets)) in steps.enumerate() {
+                let n = i + 1;
+                params.push(whole_param(
+                    format!("{} note", n),
+                    ParamValue::Note(note, scale),
+                    0.0,
+                    127.0,
+                ));
+                params.push(param(format!("{} chance", n), chance, 0.0, 1.0, 0.1));
+                params.push(whole_param(
+                    format!("{} ratchet", n),
+                    ParamValue::Byte(ratchets),
+                    1.0,
+                    MAX_RATCHETS as f32,
+                ));
+            }
+            params.push(param("clock x", &mut seq.clock_div, 0.25, 4.0, 0.25));
+            params.push(param("A4 hz", &mut seq.root_hz, 400.0, 480.0, 1.0));
+            params
+        }
+        CardClass::Envelope(env) => vec![
+            param("attack", &mut env.attack, 0.0, 4.0, 0.05),
+            param("decay", &mut env.decay, 0.0, 4.0, 0.05),
+            param("sustain", &mut env.sustain, 0.0, 1.0, 0.05),
+            param("release", &mut env.release, 0.0, 4.0, 0.05),
+            param("velocity", &mut env.velocity_amount, 0.0, 1.0, 0.05),
+        ],
+        CardClass::Delay(delay) => vec![
+            param("time", &mut delay.delay_time, 0.01, MAX_DELAY_TIME, 0.01),
+            param("feedback", &mut delay.feedback, 0.0, 0.95, 0.05),
+            param("wet", &mut delay.wet, 0.0, 1.0, 0.05),
+        ],
+        CardClass::Noise(noise) => vec![param("level", &mut noise.amplitude, 0.0, 1.0, 0.05)],
+        CardClass::Sampler(sampler) => vec![param(
+            "pitch",
+            &mut sampler.pitch,
+            MIN_SAMPLER_PITCH,
+            MAX_SAMPLER_PITCH,
+            0.05,
+        )],
+        CardClass::Filter(filter) => vec![
+            log_param("cutoff", &mut filter.cutoff, 20.0, 20000.0),
+            param("resonance", &mut filter.resonance, 0.0, 1.0, 0.05),
+        ],
+        CardClass::Lfo(lfo) => vec![
+            log_param("rate", &mut lfo.rate, MIN_LFO_RATE, MAX_LFO_RATE),
+            param("depth", &mut lfo.depth, 0.0, 1.0, 0.01),
+        ],
+        CardClass::Mixer(mixer) => vec![
+            param("gain a", &mut mixer.gain_a, 0.0, 1.5, 0.05),
+            param("gain b", &mut mixer.gain_b, 0.0, 1.5, 0.05),
+        ],
+        CardClass::Vca(vca) => vec![param("gain", &mut vca.gain, 0.0, MAX_VCA_GAIN, 0.05)],
+        CardClass::Bitcrush(crush) => vec![
+            whole_param("bits", ParamValue::Byte(&mut crush.bits), 1.0, 16.0),
+            whole_param(
+                "downsample",
+                ParamValue::Count(&mut crush.downsample),
+                1.0,
+                MAX_DOWNSAMPLE as f32,
+            ),
+        ],
+        CardClass::Chorus(chorus) => vec![
+            log_param("rate", &mut chorus.rate, MIN_CHORUS_RATE, MAX_CHORUS_RATE),
+            param("depth", &mut chorus.depth, 0.0, 1.0, 0.05),
+            param("mix", &mut chorus.mix, 0.0, 1.0, 0.05),
+            param(
+                "feedback",
+                &mut chorus.feedback,
+                0.0,
+                MAX_CHORUS_FEEDBACK,
+                0.05,
+            ),
+        ],
+        CardClass::Eq(eq) => vec![
+            param("low dB", &mut eq.low, -EQ_MAX_GAIN, EQ_MAX_GAIN, 1.0),
+            param("mid dB", &mut eq.mid, -EQ_MAX_GAIN, EQ_MAX_GAIN, 1.0),
+            log_param("mid hz", &mut eq.mid_freq, EQ_MIN_MID_FREQ, EQ_MAX_MID_FREQ),
+            param("high dB", &mut eq.high, -EQ_MAX_GAIN, EQ_MAX_GAIN, 1.0),
+        ],
+        CardClass::Compressor(comp) => vec![
+            param(
+                "thresh dB",
+                &mut comp.threshold,
+                MIN_COMP_THRESHOLD,
+                0.0,
+                1.0,
+            ),
+            param("ratio", &mut comp.ratio, 1.0, MAX_COMP_RATIO, 0.5),
+            log_param("attack", &mut comp.attack, MIN_COMP_ATTACK, MAX_COMP_ATTACK),
+            log_param(
+                "release",
+                &mut comp.release,
+                MIN_COMP_RELEASE,
+                MAX_COMP_RELEASE,
+            ),
+            param("makeup dB", &mut comp.makeup, 0.0, MAX_COMP_MAKEUP, 1.0),
+        ],
+        CardClass::Reverb(reverb) => vec![
+            param("room", &mut reverb.room_size, 0.0, 1.0, 0.05),
+            param("damping", &mut reverb.damping, 0.0, 1.0, 0.05),
+            param("wet", &mut reverb.wet, 0.0, 1.0, 0.05),
+        ],
+        CardClass::Arpeggiator(arp) => vec![whole_param(
+            "octaves",
+            ParamValue::Byte(&mut arp.octaves),
+            1.0,
+            MAX_ARP_OCTAVES as f32,
+        )],
+    }
+}
+
+// Up and Down move the inspector focus, Left and Right nudge the focused parameter
+fn inspector_key_pressed(card: &mut Card, row: &mut usize, key: Key) -> bool {
+    let mut params = card_params(&mut card.class);
+    if params.is_empty() {
+        return false;
+    }
+    *row = (*row).min(params.len() - 1);
+    match key {
+        Key::Up => *row = row.saturating_sub(1),
+        Key::Down => *row = (*row + 1).min(params.len() - 1),
+        Key::Left => params[*row].nudge(-1.0),
+        Key::Right => params[*row].nudge(1.0),
+        _ => return false,
+    }
+    true
+}
+
+fn output_device_names(host: &audio::Host) -> Vec<String> {
+    match host.output_devices() {
+        Ok(devices) => devices.filter_map(|device| device.name().ok()).collect(),
+        Err(err) => {
+            eprintln!("Couldn't list output devices: {}", err);
+            vec![]
+        }
+    }
+}
+
+// F8 steps through the output devices and back to the system default
+fn cycle_output_device(model: &mut Model) {
+    if model.output_devices.is_empty() {
+        return;
+    }
+    let next = match model.output_device {
+        None => Some(0),
+        Some(i) if i + 1 < model.output_devices.len() => Some(i + 1),
+        Some(_) => None,
+    };
+    let name = next.map(|i| model.output_devices[i].clone());
+    if let Err(err) = open_output(model, name.as_deref()) {
+        // The old stream is only replaced once the new one is running
+        eprintln!(
+            "Couldn't open {}: {}",
+            name.as_deref().unwrap_or("the default output"),
+            err
+        );
+        return;
+    }
+    model.output_device = next;
+}
+
+// Builds a stream on the named device, or the default one, and swaps it in for the old
+// stream, which is closed as it's dropped. The new audio thread starts from the defaults and
+// is brought up to date with everything the UI has set.
+fn open_output(model: &mut Model, name: Option<&str>) -> Result<(), String> {
+    let (scope, scope_consumer) = rtrb::RingBuffer::new(4 * SCOPE_HISTORY);
+    let (events, event_consumer) = rtrb::RingBuffer::new(EVENT_QUEUE);
+    let audio_model = new_audio(
+        scope,
+        event_consumer,
+        [
+            model.peak.clone(),
+            model.load.clone(),
+            model.reduction.clone(),
+        ],
+        model.clock.clone(),
+        model.clock_epoch,
+    );
+    let mut builder = model
+        .audio_host
+        .new_output_stream(audio_model)
+        .render(audio);
+    if let Some(name) = name {
+        let device = model
+            .audio_host
+            .output_devices()
+            .map_err(|err| err.to_string())?
+            .find(|device| device.name().ok().as_deref() == Some(name))
+            .ok_or("device not found")?;
+        builder = builder.device(device);
+    }
+    let stream = builder.build().map_err(|err| err.to_string())?;
+    send_delay_buffers(&stream);
+    stream.play().map_err(|err| err.to_string())?;
+    // The recording ends with the old stream, and only once the new one is running
+    if model.recording {
+        stop_recording(model);
+    }
+    model.stream = stream;
+    model.scope_consumer = scope_consumer;
+    model.events = events;
+
+    let params = model.sent_params.clone();
+    let volume = model.master_volume;
+    let (drive, bypass) = (model.drive, model.clipper_bypass);
+    let (wet, width, latch) = (model.wet, model.width, model.latch);
+    let bend = model.pitch_bend;
+    model
+        .stream
+        .send(move |audio| {
+            params.apply(audio);
+            audio.master_volume = volume;
+            audio.volume = volume;
+            audio.drive = drive;
+            audio.clipper_bypass = bypass;
+            audio.wet_target = wet;
+            audio.wet = wet;
+            audio.width_target = width;
+            audio.width = width;
+            audio.latch = latch;
+            audio.pitch_bend_target = bend;
+            audio.pitch_bend = bend;
+        })
+        .unwrap();
+    send_glide(model);
+    // The sample is loaded again on the next frame
+    model.sample_path = None;
+    Ok(())
+}
+
+// Audio thread state at its defaults. The meters are the output peak, callback load and
+// compressor gain reduction, in that order.
+fn new_audio(
+    scope: rtrb::Producer<f32>,
+    events: rtrb::Consumer<TimedEvent>,
+    meters: [Arc<AtomicU32>; 3],
+    clock: Arc<AudioClock>,
+    clock_epoch: Instant,
+) -> Audio {
+    let [peak, load, reduction] = meters;
+    Audio {
+        free_hz: 440.0,
+        last_hz: 440.0,
+        glide_time: DEFAULT_GLIDE_TIME as f64,
+        voices: [Voice::new(); MAX_VOICES],
+        notes_started: 0,
+        phase_seed: 0x9E37_79B9,
+        voice_gain: 1.0,
+        play_gain: 0.0,
+        lfo: LfoState::new(),
+        oscillators: [OscillatorState::new(); MAX_OSCILLATORS],
+        noise_color: NoiseColor::White,
+        noise_amplitude: 0.5,
+        noise_seed: 0x1234_5678,
+        pink_state: [0.0; 3],
+        sampler: SamplerState::new(),
+        filter: FilterState::new(),
+        delay: DelayState::new(),
+        crusher: BitcrushState::new(),
+        vcas: [VcaState::new(); MAX_VCAS],
+        reverb: ReverbState::new(),
+        chorus: ChorusState::new(),
+        eq: EqState::new(),
+        compressor: CompressorState::new(),
+        order: vec![],
+        branch: vec![],
+        routes: vec![],
+        envelope_level: 0.0,
+        mixer_gains: [1.0; 2],
+        master_volume: DEFAULT_MASTER_VOLUME,
+        volume: DEFAULT_MASTER_VOLUME,
+        peak,
+        load,
+        reduction,
+        drive: 1.0,
+        clipper_bypass: false,
+        recorder: None,
+        scope,
+        click: ClickState::new(),
+        pitch_bend_target: 0.0,
+        pitch_bend: 0.0,
+        wet_target: 1.0,
+        wet: 1.0,
+        latch: false,
+        width_target: 1.0,
+        width: 1.0,
+        events,
+        frame_clock: 0,
+        clock,
+        clock_epoch,
+    }
+}
+
+fn model(app: &App) -> Model {
+    app.new_window()
+        .key_pressed(key_pressed)
+        .key_released(key_released)
+        .mouse_pressed(mouse_pressed)
+        .mouse_released(mouse_released)
+        .mouse_wheel(mouse_wheel)
+        .resized(resized)
+        .view(view)
+        .build()
+        .unwrap();
+
+    let audio_host = audio::Host::new();
+
+    // Carries mono output samples from the audio thread to the oscilloscope
+    let (scope_producer, scope_consumer) = rtrb::RingBuffer::new(4 * SCOPE_HISTORY);
+    let peak = Arc::new(AtomicU32::new(0));
+    let load = Arc::new(AtomicU32::new(0));
+    let reduction = Arc::new(AtomicU32::new(0));
+    // Sequenced notes reach the audio thread timestamped with the frame they play on
+    let (events, event_consumer) = rtrb::RingBuffer::new(EVENT_QUEUE);
+    let clock = Arc::new(AudioClock {
+        frames: AtomicU64::new(0),
+        nanos: AtomicU64::new(0),
+    });
+    let clock_epoch = Instant::now();
+
+    let audio_model = new_audio(
+        scope_producer,
+        event_consumer,
+        [peak.clone(), load.clone(), reduction.clone()],
+        clock.clone(),
+        clock_epoch,
+    );
+
+    let stream = audio_host
+        .new_output_stream(audio_model)
+        .render(audio)
+        .build()
+        .unwrap();
+
+    send_delay_buffers(&stream);
+    stream.play().unwrap();
+
+    // The microphone stream is built paused and only runs while listening is switched on
+    let (onset_producer, onsets) = rtrb::RingBuffer::new(ONSET_QUEUE);
+    let mic = audio_host
+        .new_input_stream(OnsetDetector::new(onset_producer))
+        .capture(detect_onsets)
+        .build()
+        .map_err(|err| eprintln!("No microphone for onset triggers: {}", err))
+        .ok();
+    if let Some(mic) = &mic {
+        // Some backends start a stream as soon as it's built
+        mic.pause().ok();
+    }
+
+    let grid_size = fit_grid_size(app.window_rect(), GRID_COLUMNS);
+    let grid_slots = create_grid_slots(app.window_rect(), grid_size, GRID_COLUMNS, GRID_ROWS);
+
+    let (midi_sender, midi_events) = mpsc::channel();
+    let osc_receiver = osc::receiver(OSC_PORT)
+        .map_err(|err| eprintln!("Couldn't listen for OSC on port {}: {}", OSC_PORT, err))
+        .ok();
+    let midi_connection = connect_midi(midi_sender);
+
+    Model {
+        stream,
+        output_devices: output_device_names(&audio_host),
+        audio_host,
+        output_device: None,
+        is_mouse_pressed: false,
+        cards: vec![
+            Card::new(
+                0.0,
+                0.0,
+                CardClass::Oscillator(Oscillator {
+                    waveform: Waveform::Sine,
+                    pulse_width: 0.5,
+                    modulator: None,
+                    sub_level: 0.0,
+                    sub_octaves: 1,
+                    unison: 1,
+                    detune_cents: 10.0,
+                    pan: 0.0,
+                }),
+            ),
+            Card::new(
+                100.0,
+                0.0,
+                CardClass::Sequencer(Sequencer {
+                    sequence: vec![65, 69, 72, 69],
+                    gates: vec![true, true, false, true],
+                    probabilities: vec![1.0; 4],
+                    ratchets: vec![1; 4],
+                    clock_div: 1.0,
+                    clock: 0.0,
+                    ratchet: None,
+                    step: 0,
+                    root_hz: 440.0,
+                    scale: Scale::Chromatic,
+                    target: SeqTarget::Pitch,
+                }),
+            ),
+            Card::new(
+                200.0,
+                0.0,
+                CardClass::Envelope(Envelope {
+                    attack: 0.1,
+                    decay: 1.0,
+                    sustain: 0.4,
+                    release: 0.5,
+                    curve: EnvCurve::Linear,
+                    velocity_amount: 1.0,
+                }),
+            ),
+            Card::new(
+                300.0,
+                0.0,
+                CardClass::Delay(Delay {
+                    delay_time: 0.5,
+                    feedback: 0.5,
+                    wet: 0.5,
+                    sync: None,
+                    ping_pong: false,
+                    frozen: false,
+                }),
+            ),
+            Card::new(
+                -200.0,
+                -100.0,
+                CardClass::Oscillator(Oscillator {
+                    waveform: Waveform::Saw,
+                    pulse_width: 0.5,
+                    modulator: None,
+                    sub_level: 0.0,
+                    sub_octaves: 1,
+                    unison: 1,
+                    detune_cents: 10.0,
+                    pan: 0.0,
+                }),
+            ),
+            Card::new(
+                -100.0,
+                -100.0,
+                CardClass::Noise(Noise {
+                    color: NoiseColor::White,
+                    amplitude: 0.5,
+                }),
+            ),
+            Card::new(
+                -300.0,
+                -100.0,
+                CardClass::Filter(Filter {
+                    cutoff: 1200.0,
+                    resonance: 0.3,
+                    mode: FilterMode::LowPass,
+                }),
+            ),
+            Card::new(
+                -400.0,
+                -100.0,
+                CardClass::Lfo(Lfo {
+                    rate: 5.0,
+                    depth: 0.02,
+                    shape: Waveform::Sine,
+                    target: ModDest::Pitch,
+                }),
+            ),
+            Card::new(
+                400.0,
+                -300.0,
+                CardClass::Mixer(Mixer {
+                    gain_a: 1.0,
+                    gain_b: 1.0,
+                }),
+            ),
+            Card::new(
+                300.0,
+                -300.0,
+                CardClass::Bitcrush(Bitcrush {
+                    bits: 8,
+                    downsample: 4,
+                }),
+            ),
+            Card::new(
+                200.0,
+                -300.0,
+                CardClass::Reverb(Reverb {
+                    room_size: 0.6,
+                    damping: 0.5,
+                    wet: 0.3,
+                }),
+            ),
+            Card::new(
+                100.0,
+                -300.0,
+                CardClass::Chorus(Chorus {
+                    rate: 0.8,
+                    depth: 0.5,
+                    mix: 0.5,
+                    feedback: 0.0,
+                }),
+            ),
+            Card::new(
+                0.0,
+                -300.0,
+                CardClass::Arpeggiator(Arpeggiator {
+                    mode: ArpMode::Up,
+                    octaves: 1,
+                    rate: BeatDivision::Eighth,
+                    clock: 0.0,
+                    step: 0,
+                }),
+            ),
+        ],
+        is_updating: false,
+        swap: None,
+        presets: scan_presets(),
+        preset: None,
+        grid_slots,
+        grid_cols: GRID_COLUMNS,
+        grid_rows: GRID_ROWS,
+        grid_size,
+        palette: palette_classes(),
+        selected_card: None,
+        solo: None,
+        drag_origin: pt2(0.0, 0.0),
+        sweep: None,
+        wheel: None,
+        inspector_row: 0,
+        undo_stack: VecDeque::with_capacity(MAX_HISTORY + 1),
+        redo_stack: vec![],
+        hand: vec![],
+        rows: Rows::default(),
+        bpm: DEFAULT_BPM,
+        tempo: DEFAULT_BPM,
+        clock_source: ClockSource::Internal,
+        last_clock_stamp: None,
+        last_clock_time: 0.0,
+        pulse_interval: 0.0,
+        midi_running: false,
+        last_update: 0.0,
+        beat_time: 0.0,
+        beat_crossed: false,
+        beat_step: 0,
+        step_duration: 0.5,
+        beat_delta: 0.0,
+        swing: 0.0,
+        seed: DEFAULT_SEED,
+        rng: StdRng::seed_from_u64(DEFAULT_SEED),
+        glide_time: DEFAULT_GLIDE_TIME,
+        glide: true,
+        metronome_on: false,
+        sent_params: SoundParams::default(),
+        mod_routes: vec![],
+        mic,
+        onsets,
+        events,
+        clock,
+        clock_epoch,
+        listening: false,
+        onset: false,
+        snapshot_a: None,
+        snapshot_b: None,
+        morph: 0.0,
+        sample_path: None,
+        taps: VecDeque::with_capacity(TAP_HISTORY),
+        playing: true,
+        restart: false,
+        pitch_bend: 0.0,
+        beats_per_bar: BEATS_PER_BAR,
+        master_volume: DEFAULT_MASTER_VOLUME,
+        wet: 1.0,
+        width: 1.0,
+        drive: 1.0,
+        clipper_bypass: false,
+        peak,
+        meter_level: 0.0,
+        load,
+        cpu_load: 0.0,
+        reduction,
+        reduction_level: 0.0,
+        reduction_hold: 0.0,
+        reduction_hold_time: 0.0,
+        clip_time: -CLIP_HOLD_TIME,
+        recording: false,
+        scope_consumer,
+        scope_samples: VecDeque::with_capacity(SCOPE_HISTORY),
+        fft: FftPlanner::new().plan_fft_forward(FFT_SIZE),
+        fft_buffer: vec![Complex::new(0.0, 0.0); FFT_SIZE],
+        spectrum: vec![0.0; SPECTRUM_BARS],
+        held_notes: vec![],
+        latch: false,
+        quantize: false,
+        pending_drop: false,
+        lane_levels: vec![],
+        humanize: 0.0,
+        humanize_rng: StdRng::from_entropy(),
+        keyboard_octave: 0,
+        keyboard_keys: vec![],
+        gate: false,
+        midi_events,
+        osc_receiver,
+        _midi_connection: midi_connection,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ClockSource {
+    Internal,
+    Midi,
+}
+
+enum MidiEvent {
+    NoteOn { note: u8, velocity: u8 },
+    NoteOff { note: u8 },
+    Clock { stamp: u64 },
+    Start,
+    Continue,
+    Stop,
+}
+
+fn parse_midi(stamp: u64, message: &[u8]) -> Option<MidiEvent> {
+    match *message {
+        [0xF8] => Some(MidiEvent::Clock { stamp }),
+        [0xFA] => Some(MidiEvent::Start),
+        [0xFB] => Some(MidiEvent::Continue),
+        [0xFC] => Some(MidiEvent::Stop),
+        // A note-on with zero velocity is the common way to send note-off
+        [status, note, 0] if status & 0xF0 == 0x90 => Some(MidiEvent::NoteOff { note }),
+        [status, note, velocity] if status & 0xF0 == 0x90 => {
+            Some(MidiEvent::NoteOn { note, velocity })
+        }
+        [status, note, _] if status & 0xF0 == 0x80 => Some(MidiEvent::NoteOff { note }),
+        _ => None,
+    }
+}
+
+// Opens the first MIDI input port, if there is one. The callback runs on midir's own
+// thread, so events are queued for `update` to play.
+fn connect_midi(sender: Sender<MidiEvent>) -> Option<MidiInputConnection<()>> {
+    let input = MidiInput::new("synth-rs").ok()?;
+    let port = input.ports().into_iter().next()?;
+    input
+        .connect(
+            &port,
+            "synth-rs-input",
+            move |stamp, message, _| {
+                if let Some(event) = parse_midi(stamp, message) {
+                    sender.send(event).ok();
+                }
+            },
+            (),
+        )
+        .ok()
+}
+
+const GRID_SIZE: f32 = 110.0;
+const GRID_COLUMNS: usize = 5;
+const MIN_GRID_COLUMNS: usize = 2;
+const MAX_GRID_COLUMNS: usize = 12;
+// The chain row and the branch row; the regions have no meaning for any more
+const GRID_ROWS: usize = 2;
+// Where the first column sits, clear of the palette, and the room kept right of the last
+// one for the meters
+const GRID_LEFT: f32 = 2.6 * GRID_SIZE;
+const GRID_RIGHT_MARGIN: f32 = 60.0;
+
+fn resized(app: &App, model: &mut Model, _size: Vec2) {
+    regrid(app.window_rect(), model);
+}
+
+// Ctrl+Left/Right change the columns and Ctrl+Up/Down the rows
+fn resize_grid(app: &App, model: &mut Model, cols: isize, rows: isize) {
+    let cols = model.grid_cols.saturating_add_signed(cols);
+    let rows = model.grid_rows.saturating_add_signed(rows);
+    model.grid_cols = cols.clamp(MIN_GRID_COLUMNS, MAX_GRID_COLUMNS);
+    model.grid_rows = rows.clamp(1, GRID_ROWS);
+    regrid(app.window_rect(), model);
+}
+
+// Slot spacing that fits the columns between the palette and the meters
+fn fit_grid_size(win: Rect, cols: usize) -> f32 {
+    let room = win.w() - GRID_LEFT - GRID_RIGHT_MARGIN;
+    GRID_SIZE.min(room / (cols as f32 - 0.5)).max(1.0)
+}
+
+// Rebuilds the grid for the window size and dimensions. Each card moves to the slot in the
+// same row and column it sat in, so the chain keeps its order and off-grid cards keep their
+// offset from it. Cards whose slot is gone take the nearest free one, or go to the hand.
+fn regrid(win: Rect, model: &mut Model) {
+    let size = fit_grid_size(win, model.grid_cols);
+    let slots = create_grid_slots(win, size, model.grid_cols, model.grid_rows);
+    let old = std::mem::take(&mut model.grid_slots);
+    let hand_y = slots.iter().map(|slot| slot.y).reduce(f32::min).unwrap();
+    let mut occupied: Vec<Point2> = vec![];
+    let mut lost = vec![];
+    for (i, card) in model.cards.iter_mut().enumerate() {
+        card.w = CARD_WIDTH * size / GRID_SIZE;
+        card.h = CARD_HEIGHT * size / GRID_SIZE;
+        if card.dragging {
+            continue;
+        }
+        let dist = |slot: &Point2| distance(card.x_targ, card.y_targ, slot.x, slot.y);
+        let Some(&from) = old.iter().min_by(|a, b| dist(a).total_cmp(&dist(b))) else {
+            continue;
+        };
+        let offset = pt2(card.x_targ, card.y_targ) - from;
+        let region = region_at(from.y, win);
+        if region == Region::Hand {
+            // The hand is laid out again along its new row
+            card.y_targ = hand_y + offset.y;
+            continue;
+        }
+        let column = old.iter().filter(|s| s.y == from.y && s.x < from.x).count();
+        let row = slots
+            .iter()
+            .filter(|slot| region_at(slot.y, win) == region)
+            .nth(column);
+        match row {
+            Some(&slot) => {
+                card.x_targ = slot.x + offset.x;
+                card.y_targ = slot.y + offset.y;
+                occupied.push(slot);
+            }
+            None => lost.push(i),
+        }
+    }
+    let patch_slots: Vec<Point2> = slots.iter().copied().filter(|s| s.y != hand_y).collect();
+    for i in lost {
+        let card = &mut model.cards[i];
+        let (x, y) = snap_to_grid(card.x_targ, card.y_targ, &patch_slots, &occupied)
+            .unwrap_or((card.x_targ, hand_y));
+        occupied.push(pt2(x, y));
+        card.x_targ = x;
+        card.y_targ = y;
+    }
+    model.grid_slots = slots;
+    model.grid_size = size;
+    model.is_updating = true;
+}
+
+// The hand row along the bottom, the chain row across the middle, and with two rows the
+// branch row above it
+fn create_grid_slots(win: Rect, grid_size: f32, cols: usize, rows: usize) -> Vec<Point2> {
+    let mut grid_slots = vec![];
+    let middle_y = win.bottom() + win.h() / 2.0;
+    let bottom_y = win.bottom() + GRID_SIZE;
+    let mut row_ys = vec![middle_y, bottom_y];
+    if rows > 1 {
+        row_ys.insert(0, middle_y + BRANCH_ROW_OFFSET);
+    }
+    for y in row_ys {
+        for i in 0..cols {
+            let x = win.left() + GRID_LEFT + i as f32 * grid_size;
+            grid_slots.push(pt2(x, y));
+        }
+    }
+    grid_slots
+}
+
+// Per-sample voice state shared by every stage in the frame
+struct VoiceFrame {
+    levels: [f32; MAX_VOICES], // Envelope output
+    gates: [f32; MAX_VOICES],  // Oscillator level before any envelope
+    gain: f32,
+    oscillator_scale: f32,
+}
+
+// The signal at one point in the chain: the shared stereo signal plus each voice's own
+#[derive(Clone, Copy)]
+struct Signal {
+    amp: [f32; 2],
+    voices: [[f32; 2]; MAX_VOICES],
+}
+
+impl Signal {
+    fn new() -> Self {
+        Signal {
+            amp: [0.0; 2],
+            voices: [[0.0; 2]; MAX_VOICES],
+        }
+    }
+
+    // Adds the per-voice signals to the shared stereo signal, leaving them empty
+    fn mix(&mut self, gain: f32) {
+        for voice in self.voices.iter() {
+            self.amp[0] += voice[0] * gain;
+            self.amp[1] += voice[1] * gain;
+        }
+        self.voices = [[0.0; 2]; MAX_VOICES];
+    }
+}
+
+// Runs one frame through the stages in order. At a mixer stage the branch is rendered
+// from scratch and blended in. `dry` follows the same path with the effects left out,
+// for the global dry/wet mix.
+fn process_stages(
+    audio: &mut Audio,
+    stages: &[Stage],
+    branch: &[Stage],
+    voices: &VoiceFrame,
+    wet: &mut Signal,
+    dry: &mut Signal,
+) {
+    for stage in stages {
+        match *stage {
+            Stage::Oscillator(index) => {
+                let osc = &audio.oscillators[index];
+                for (i, voice) in audio.voices.iter().enumerate() {
+                    if voices.gates[i] > 0.0 {
+                        let sample =
+                            osc.sample(voice, index) * voices.oscillator_scale * voices.gates[i];
+                        for signal in [&mut *wet, &mut *dry] {
+                            signal.voices[i][0] += sample * osc.pan_gains[0];
+                            signal.voices[i][1] += sample * osc.pan_gains[1];
+                        }
+                    }
+                }
+            }
+            Stage::Noise => {
+                let white = white_noise(&mut audio.noise_seed);
+                let noise = match audio.noise_color {
+                    NoiseColor::White => white,
+                    NoiseColor::Pink => pink_noise(white, &mut audio.pink_state),
+                };
+                for signal in [&mut *wet, &mut *dry] {
+                    signal.amp = signal.amp.map(|x| x + noise * audio.noise_amplitude);
+                }
+            }
+            Stage::Sampler => {
+                let sample = audio.sampler.next();
+                for signal in [&mut *wet, &mut *dry] {
+                    signal.amp = signal.amp.map(|x| x + sample);
+                }
+            }
+            Stage::Envelope => {
+                // Signal shared by all voices follows the loudest of them
+                let loudest = voices.levels.iter().copied().fold(0.0, f32::max);
+                for signal in [&mut *wet, &mut *dry] {
+                    for (amp, level) in signal.voices.iter_mut().zip(voices.levels) {
+                        *amp = amp.map(|x| x * level);
+                    }
+                    signal.amp = signal.amp.map(|x| x * loudest);
+                }
+            }
+            Stage::Delay => {
+                dry.mix(voices.gain);
+                wet.mix(voices.gain);
+                wet.amp = audio.delay.process(wet.amp);
+            }
+            Stage::Filter => {
+                dry.mix(voices.gain);
+                wet.mix(voices.gain);
+                wet.amp = audio.filter.process(wet.amp);
+            }
+            Stage::Bitcrush => {
+                dry.mix(voices.gain);
+                wet.mix(voices.gain);
+                wet.amp = audio.crusher.process(wet.amp);
+            }
+            Stage::Vca(index) => {
+                dry.mix(voices.gain);
+                wet.mix(voices.gain);
+                wet.amp = audio.vcas[index].process(wet.amp);
+            }
+            Stage::Reverb => {
+                dry.mix(voices.gain);
+                wet.mix(voices.gain);
+                wet.amp = audio.reverb.process(wet.amp);
+            }
+            Stage::Chorus => {
+                dry.mix(voices.gain);
+                wet.mix(voices.gain);
+                wet.amp = audio.chorus.process(wet.amp);
+            }
+            Stage::Eq => {
+                dry.mix(voices.gain);
+                wet.mix(voices.gain);
+                wet.amp = audio.eq.process(wet.amp);
+            }
+            Stage::Compressor => {
+                dry.mix(voices.gain);
+                wet.mix(voices.gain);
+                wet.amp = audio.compressor.process(wet.amp);
+            }
+            Stage::Mixer => {
+                // An empty branch just contributes silence
+                let mut branch_wet = Signal::new();
+                let mut branch_dry = Signal::new();
+                process_stages(audio, branch, &[], voices, &mut branch_wet, &mut branch_dry);
+                let [gain_a, gain_b] = audio.mixer_gains;
+                for (signal, mut branch) in [(&mut *wet, branch_wet), (&mut *dry, branch_dry)] {
+                    signal.mix(voices.gain);
+                    branch.mix(voices.gain);
+                    for (a, b) in signal.amp.iter_mut().zip(branch.amp) {
+                        *a = *a * gain_a + b * gain_b;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn audio(audio: &mut Audio, buffer: &mut Buffer) {
+    let render_start = Instant::now();
+    let since_epoch = render_start.duration_since(audio.clock_epoch);
+    audio
+        .clock
+        .nanos
+        .store(since_epoch.as_nanos() as u64, Ordering::Relaxed);
+    audio
+        .clock
+        .frames
+        .store(audio.frame_clock, Ordering::Relaxed);
+    let sample_rate = buffer.sample_rate() as f64;
+    let dt = 1.0 / sample_rate as f32;
+    // One-pole smoothing towards the target pitch; a zero glide time jumps instantly
+    let glide = if audio.glide_time > 0.0 {
+        (-1.0 / (audio.glide_time * sample_rate)).exp()
+    } else {
+        0.0
+    };
+    let fade_step = (1.0 / (OSCILLATOR_FADE_TIME * sample_rate)) as f32;
+    let stereo = buffer.channels() > 1;
+    let active_oscillators = audio.oscillators.iter().filter(|o| o.active).count();
+    let playing = active_oscillators > 0;
+    audio.filter.update_coefficients(sample_rate as f32);
+    audio.eq.update_coefficients(sample_rate as f32);
+    audio.compressor.sample_rate = sample_rate as f32;
+    audio.compressor.peak_reduction = 0.0;
+    audio.delay.prepare(sample_rate as f32);
+    audio.reverb.prepare(sample_rate as f32);
+    audio.chorus.sample_rate = sample_rate as f32;
+    audio.sampler.sample_rate = sample_rate as f32;
+    // Smoothers cover the same time whatever the device's rate
+    let volume_smoothing = dsp::smoothing(VOLUME_SMOOTHING, sample_rate as f32);
+    let bend_smoothing = dsp::smoothing(PITCH_BEND_SMOOTHING, sample_rate as f32);
+    let voice_gain_smoothing = dsp::smoothing(VOICE_GAIN_SMOOTHING, sample_rate as f32);
+    for vca in audio.vcas.iter_mut() {
+        vca.smoothing = volume_smoothing;
+    }
+    // Taken for the duration of the buffer so stages can mutate the rest of `audio`
+    let order = std::mem::take(&mut audio.order);
+    let branch = std::mem::take(&mut audio.branch);
+    // Without an envelope card a voice sounds at its velocity for as long as it runs
+    let has_envelope = order.contains(&Stage::Envelope) || branch.contains(&Stage::Envelope);
+
+    for frame in buffer.frames_mut() {
+        // Timed events land on their own frame; late ones play straight away
+        while let Some(kind) = next_due(&mut audio.events, audio.frame_clock) {
+            audio.play_event(kind);
+        }
+        audio.frame_clock += 1;
+
+        // The chain runs in stereo; sources are centred. Oscillators render into each
+        // voice separately until a stage needs the mixed signal.
+        let mut wet = Signal::new();
+        let mut dry = Signal::new();
+        let lfo = audio.lfo.next(sample_rate);
+        let mods = resolve_routes(&audio.routes, lfo, audio.envelope_level);
+        let pitch_mod = mods.pitch * MOD_PITCH_OCTAVES;
+        audio.filter.cutoff_mod = mods.cutoff * MOD_CUTOFF_OCTAVES;
+        audio.pitch_bend += (audio.pitch_bend_target - audio.pitch_bend) * bend_smoothing;
+        let phase_scale = (pitch_mod + audio.pitch_bend / 12.0).exp2() as f64 / sample_rate;
+
+        let mut levels = [0.0; MAX_VOICES];
+        let mut gates = [0.0; MAX_VOICES];
+        let mut sounding = 0;
+        for (i, voice) in audio.voices.iter_mut().enumerate() {
+            levels[i] = voice.next(dt, glide, phase_scale, &audio.oscillators);
+            if !voice.is_free() {
+                gates[i] = if has_envelope {
+                    1.0
+                } else {
+                    voice.envelope.velocity * voice.fade
+                };
+                sounding += 1;
+            }
+        }
+        audio.envelope_level = if has_envelope {
+            levels.iter().copied().fold(0.0, f32::max)
+        } else {
+            0.0
+        };
+        audio.play_gain = dsp::ramp(audio.play_gain, playing, fade_step);
+        let oscillator_scale = audio.play_gain / active_oscillators.max(1) as f32;
+        let target_gain = 1.0 / (sounding.max(1) as f32).sqrt();
+        audio.voice_gain += (target_gain - audio.voice_gain) * voice_gain_smoothing;
+        let voice_gain = audio.voice_gain;
+
+        for (index, osc) in audio.oscillators.iter_mut().enumerate() {
+            osc.fade(fade_step);
+            // Oscillators whose card just left the chain fade out instead of cutting off
+            if !osc.active && osc.gain > 0.0 {
+                for (i, voice) in audio.voices.iter().enumerate() {
+                    let sample = osc.sample(voice, index) * oscillator_scale * gates[i];
+                    for signal in [&mut wet, &mut dry] {
+                        signal.voices[i][0] += sample * osc.pan_gains[0];
+                        signal.voices[i][1] += sample * osc.pan_gains[1];
+                    }
+                }
+            }
+        }
+        let voices = VoiceFrame {
+            levels,
+            gates,
+            gain: voice_gain,
+            oscillator_scale,
+        };
+        process_stages(audio, &order, &branch, &voices, &mut wet, &mut dry);
+        wet.mix(voice_gain);
+        dry.mix(voice_gain);
+        // The ends of the dry/wet range pass one signal through untouched
+        audio.wet = if (audio.wet_target - audio.wet).abs() < WET_SNAP {
+            audio.wet_target
+        } else {
+            audio.wet + (audio.wet_target - audio.wet) * volume_smoothing
+        };
+        let amp = match audio.wet {
+            w if w >= 1.0 => wet.amp,
+            w if w <= 0.0 => dry.amp,
+            w => [0, 1].map(|c| dry.amp[c] * (1.0 - w) + wet.amp[c] * w),
+        };
+        // Amplitude routes scale the whole chain and pan routes move it across the field
+        let pan = pan_gains(mods.pan);
+        let amp = [0, 1].map(|c| amp[c] * (1.0 + mods.amplitude) * pan[c]);
+        audio.width = if (audio.width_target - audio.width).abs() < WET_SNAP {
+            audio.width_target
+        } else {
+            audio.width + (audio.width_target - audio.width) * volume_smoothing
+        };
+        // A mono device sums the channels, which cancels the side signal anyway
+        let amp = if stereo {
+            apply_width(amp, audio.width)
+        } else {
+            amp
+        };
+        audio.volume += (audio.master_volume - audio.volume) * volume_smoothing;
+        let mut amp = amp.map(|x| x * audio.volume);
+        if !audio.clipper_bypass {
+            amp = amp.map(|x| soft_clip(x, audio.drive));
+        }
+        // The click is added after the clipper so it never changes how the synth sits. The
+        // sum goes through the knee again, which leaves anything below it untouched.
+        let click = audio.click.next(sample_rate) * audio.volume;
+        let amp = if audio.clipper_bypass {
+            amp.map(|x| x + click)
+        } else {
+            amp.map(|x| soft_clip(x + click, 1.0))
+        };
+        write_frame(frame, amp);
+        // Never blocks; samples are dropped if the UI falls behind
+        let _ = audio.scope.push((amp[0] + amp[1]) * 0.5);
+    }
+
+    audio.order = order;
+    audio.branch = branch;
+
+    // Non-negative floats order the same as their bits, so the UI sees the highest peak
+    // since it last looked
+    let peak = buffer
+        .iter()
+        .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+    audio.peak.fetch_max(peak.to_bits(), Ordering::Relaxed);
+    audio
+        .reduction
+        .fetch_max(audio.compressor.peak_reduction.to_bits(), Ordering::Relaxed);
+
+    // Hand the rendered buffer to the recording thread. Samples are dropped rather than
+    // blocking if the disk can't keep up.
+    if let Some(recorder) = &mut audio.recorder {
+        for &sample in buffer.iter() {
+            let _ = recorder.push(sample);
+        }
+    }
+
+    // Time spent here as a share of the time the buffer takes to play
+    let period = buffer.len_frames() as f64 / sample_rate;
+    let load = (render_start.elapsed().as_secs_f64() / period) as f32;
+    audio.load.fetch_max(load.to_bits(), Ordering::Relaxed);
+}
+
+// Mid/side width. Widening also turns the whole signal down a little, so a wide side
+// doesn't push hard-panned sounds into the clipper.
+fn apply_width(amp: [f32; 2], width: f32) -> [f32; 2] {
+    let mid = (amp[0] + amp[1]) * 0.5;
+    let side = (amp[0] - amp[1]) * 0.5 * width;
+    let gain = if width > 1.0 {
+        2.0 / (1.0 + width)
+    } else {
+        1.0
+    };
+    [(mid + side) * gain, (mid - side) * gain]
+}
+
+// Writes a stereo sample to an output frame of any channel count, summing to mono when
+// the device only has one channel
+fn write_frame(frame: &mut [f32], amp: [f32; 2]) {
+    if frame.len() == 1 {
+        frame[0] = (amp[0] + amp[1]) * 0.5;
+    } else {
+        for (i, channel) in frame.iter_mut().enumerate() {
+            *channel = amp[i % 2];
+        }
+    }
+}
+
+// Metronome click: a short decaying sine, higher on the downbeat
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ClickState {
+    phase: f64,
+    hz: f64,
+    level: f32,
+}
+
+const CLICK_HZ: f64 = 1000.0;
+const ACCENT_HZ: f64 = 1500.0;
+const CLICK_LEVEL: f32 = 0.3;
+const CLICK_DECAY_TIME: f32 = 0.015;
+
+impl ClickState {
+    fn new() -> Self {
+        ClickState {
+            phase: 0.0,
+            hz: CLICK_HZ,
+            level: 0.0,
+        }
+    }
+
+    fn start(&mut self, accent: bool) {
+        self.hz = if accent { ACCENT_HZ } else { CLICK_HZ };
+        self.phase = 0.0;
+        self.level = CLICK_LEVEL;
+    }
+
+    fn next(&mut self, sample_rate: f64) -> f32 {
+        if self.level < 1e-4 {
+            return 0.0;
+        }
+        let value = (2.0 * PI * self.phase).sin() as f32 * self.level;
+        self.phase = (self.phase + self.hz / sample_rate).fract();
+        self.level *= (-1.0 / (CLICK_DECAY_TIME * sample_rate as f32)).exp();
+        value
+    }
+}
+
+// Keeps the output inside -1..1. At unity drive anything below the knee passes unchanged
+// and only the peaks are rounded off; more drive pushes further into the tanh curve.
+fn soft_clip(x: f32, drive: f32) -> f32 {
+    let x = x * drive;
+    if x.abs() <= SOFT_CLIP_KNEE {
+        x
+    } else {
+        let over = (x.abs() - SOFT_CLIP_KNEE) / (1.0 - SOFT_CLIP_KNEE);
+        x.signum() * (SOFT_CLIP_KNEE + (1.0 - SOFT_CLIP_KNEE) * over.tanh())
+    }
+}
+
+fn key_pressed(app: &App, model: &mut Model, key: Key) {
+    if app.keys.mods.ctrl() {
+        match key {
+            Key::S => save_patch(model),
+            Key::N => begin_swap(app, model, PatchSwap::Init),
+            Key::Left => resize_grid(app, model, -1, 0),
+            Key::Right => resize_grid(app, model, 1, 0),
+            Key::Down => resize_grid(app, model, 0, -1),
+            Key::Up => resize_grid(app, model, 0, 1),
+            Key::L => load_patch(app, model),
+            Key::Z if app.keys.mods.shift() => redo(model),
+            Key::Z => undo(model),
+            Key::M => {
+                model.clock_source = match model.clock_source {
+                    ClockSource::Internal => ClockSource::Midi,
+                    ClockSource::Midi => ClockSource::Internal,
+                }
+            }
+            _ => {}
+        }
+        return;
+    }
+
+    // Keys aimed at the selected card take priority over the global bindings
+    if let Some(selected) = model.selected_card {
+        let source = ModSource::of(&model.cards[selected].class);
+        if let (Key::K, Some(source)) = (key, source) {
+            cycle_route(model, source, app.keys.mods.shift());
+            return;
+        }
+        if key == Key::O {
+            toggle_solo(model, selected);
+            return;
+        }
+        let before = snapshot(model);
+        let card = &mut model.cards[selected];
+        if inspector_key_pressed(card, &mut model.inspector_row, key)
+            || card_key_pressed(card, key, &mut model.rng)
+        {
+            let (card, old) = (&model.cards[selected], &before[selected]);
+            let rebuild = card.bypassed != old.bypassed
+                || std::mem::discriminant(&card.class) != std::mem::discriminant(&old.class);
+            if card.class != old.class || rebuild {
+                push_history(model, before);
+            }
+            // Param edits patch the chain copy so a running sequencer keeps its place
+            if rebuild {
+                model.is_updating = true;
+            } else {
+                sync_copy(model, selected);
+            }
+            return;
+        }
+    }
+
+    match key {
+        Key::F10 => toggle_listening(model),
+        Key::F8 => cycle_output_device(model),
+        Key::F6 | Key::F7 => {
+            let step = if key == Key::F6 { -0.1 } else { 0.1 };
+            let humanize = (model.humanize + step).clamp(0.0, 1.0);
+            model.humanize = (humanize * 10.0).round() / 10.0;
+        }
+        Key::F1 => model.snapshot_a = Some(take_snapshot(model)),
+        Key::F2 => model.snapshot_b = Some(take_snapshot(model)),
+        Key::F3 | Key::F4 => {
+            let step = if key == Key::F3 {
+                -MORPH_STEP
+            } else {
+                MORPH_STEP
+            };
+            set_morph(model, model.morph + step);
+        }
+        Key::Space => {
+            if model.stream.is_playing() {
+                model.stream.pause().unwrap();
+            } else {
+                model.stream.play().unwrap();
+            }
+        }
+        Key::R => {
+            if model.recording {
+                stop_recording(model);
+            } else {
+                start_recording(model);
+            }
+        }
+        Key::Up => set_master_volume(model, model.master_volume + 0.05),
+        Key::Down => set_master_volume(model, model.master_volume - 0.05),
+        Key::V if app.keys.mods.shift() => {
+            model.clipper_bypass = !model.clipper_bypass;
+            let bypass = model.clipper_bypass;
+            model
+                .stream
+                .send(move |audio| audio.clipper_bypass = bypass)
+                .unwrap();
+        }
+        Key::V => {
+            let next = DRIVE_STEPS
+                .iter()
+                .position(|&d| d > model.drive)
+                .unwrap_or(0);
+            model.drive = DRIVE_STEPS[next];
+            let drive = model.drive;
+            model.stream.send(move |audio| audio.drive = drive).unwrap();
+        }
+        Key::Semicolon | Key::Apostrophe => {
+            let step = if key == Key::Semicolon { -0.1 } else { 0.1 };
+            set_wet(model, model.wet + step);
+        }
+        Key::Key9 | Key::Key0 => {
+            let step = if key == Key::Key9 { -0.1 } else { 0.1 };
+            set_width(model, model.width + step);
+        }
+        Key::Comma => model.swing = (model.swing - 0.05).max(0.0),
+        Key::Period => model.swing = (model.swing + 0.05).min(MAX_SWING),
+        Key::Z | Key::X => {
+            let step = if key == Key::Z { -0.05 } else { 0.05 };
+            model.glide_time = (model.glide_time + step).clamp(0.0, MAX_GLIDE_TIME);
+            send_glide(model);
+        }
+        Key::L => {
+            model.glide = !model.glide;
+            send_glide(model);
+        }
+        Key::M => model.metronome_on = !model.metronome_on,
+        Key::Return => toggle_transport(model),
+        Key::PageUp => cycle_preset(app, model, -1),
+        Key::PageDown => cycle_preset(app, model, 1),
+        Key::Tab => tap_tempo(model, app.time),
+        Key::LBracket | Key::RBracket => {
+            let step = if app.keys.mods.shift() { 10.0 } else { 1.0 };
+            let step = if key == Key::LBracket { -step } else { step };
+            set_bpm(model, model.bpm + step);
+        }
+        Key::Delete => all_notes_off(app, model),
+        Key::Q => {
+            model.latch = !model.latch;
+            let latch = model.latch;
+            model
+                .stream
+                .send(move |audio| audio.set_latch(latch))
+                .unwrap();
+        }
+        Key::I => {
+            // Turning it off lets anything still waiting through at once
+            model.quantize = !model.quantize;
+            model.pending_drop = false;
+            model.is_updating = true;
+        }
+        Key::Minus | Key::Equals => {
+            let step = if key == Key::Minus { -1 } else { 1 };
+            model.keyboard_octave =
+                (model.keyboard_octave + step).clamp(MIN_KEYBOARD_OCTAVE, MAX_KEYBOARD_OCTAVE);
+        }
+        _ => {
+            if model.keyboard_keys.iter().any(|&(held, _)| held == key) {
+                return;
+            }
+            if let Some(note) = key_to_note(key, model.keyboard_octave) {
+                model.keyboard_keys.push((key, note));
+                note_on(model, note, 1.0);
+            }
+        }
+    }
+}
+
+// Changes the internal tempo, keeping the clock at the same point in the current beat
+fn set_bpm(model: &mut Model, bpm: f32) {
+    let bpm = bpm.clamp(MIN_BPM, MAX_BPM);
+    if model.tempo == model.bpm {
+        model.beat_time *= model.bpm / bpm;
+    }
+    model.bpm = bpm;
+}
+
+// Sets the tempo from the average gap between recent taps. A long pause, or a gap far
+// from the ones before it, starts a fresh count.
+fn tap_tempo(model: &mut Model, now: f32) {
+    if let Some(&last) = model.taps.back() {
+        let interval = now - last;
+        let intervals = model.taps.len() - 1;
+        let average = if intervals > 0 {
+            (last - model.taps[0]) / intervals as f32
+        } else {
+            interval
+        };
+        if interval > TAP_TIMEOUT || (interval - average).abs() > average * 0.5 {
+            model.taps.clear();
+        }
+    }
+    if model.taps.len() == TAP_HISTORY {
+        model.taps.pop_front();
+    }
+    model.taps.push_back(now);
+    if model.taps.len() >= 2 {
+        let span = model.taps.back().unwrap() - model.taps[0];
+        let interval = span / (model.taps.len() - 1) as f32;
+        set_bpm(model, 60.0 / interval);
+    }
+}
+
+fn send_glide(model: &Model) {
+    let glide_time = if model.glide {
+        model.glide_time as f64
+    } else {
+        0.0
+    };
+    model
+        .stream
+        .send(move |audio| audio.glide_time = glide_time)
+        .unwrap();
+}
+
+// Releases the note the key started, even if the octave has moved since
+fn key_released(_app: &App, model: &mut Model, key: Key) {
+    if let Some(i) = model
+        .keyboard_keys
+        .iter()
+        .position(|&(held, _)| held == key)
+    {
+        let (_, note) = model.keyboard_keys.remove(i);
+        note_off(model, note);
+    }
+}
+
+// Edits the card under the mouse, returning whether the key was used
+fn card_key_pressed(card: &mut Card, key: Key, rng: &mut StdRng) -> bool {
+    match (key, &mut card.class) {
+        (Key::W, CardClass::Oscillator(osc)) => osc.waveform = osc.waveform.next(),
+        (Key::M, CardClass::Oscillator(osc)) => {
+            osc.modulator = match osc.modulator {
+                Some(_) => None,
+                None => Some(Box::new(ModOsc {
+                    ratio: 2.0,
+                    index: 1.0,
+                })),
+            }
+        }
+        (Key::C, CardClass::Envelope(env)) => {
+            env.curve = match env.curve {
+                EnvCurve::Linear => EnvCurve::Exponential,
+                EnvCurve::Exponential => EnvCurve::Linear,
+            }
+        }
+        (Key::F, CardClass::Filter(filter)) => filter.mode = filter.mode.next(),
+        (Key::W, CardClass::Lfo(lfo)) => lfo.shape = lfo.shape.next(),
+        (Key::T, CardClass::Lfo(lfo)) => lfo.target = lfo.target.next(),
+        (Key::D, CardClass::Delay(delay)) => delay.sync = BeatDivision::cycle(delay.sync),
+        (Key::P, CardClass::Delay(delay)) => delay.ping_pong = !delay.ping_pong,
+        (Key::F, CardClass::Delay(delay)) => delay.frozen = !delay.frozen,
+        (Key::Equals, CardClass::Sequencer(seq)) => seq.push_step(),
+        (Key::Minus, CardClass::Sequencer(seq)) => seq.pop_step(),
+        (Key::C, CardClass::Sequencer(seq)) => seq.set_scale(seq.scale.next()),
+        (Key::P, CardClass::Sequencer(seq)) => seq.randomize(rng),
+        (Key::T, CardClass::Sequencer(seq)) => seq.target = seq.target.next(),
+        (Key::W, CardClass::Arpeggiator(arp)) => arp.mode = arp.mode.next(),
+        (Key::D, CardClass::Arpeggiator(arp)) => {
+            arp.rate = BeatDivision::cycle(Some(arp.rate)).unwrap_or(BeatDivision::Half)
+        }
+        (Key::L, CardClass::Sampler(sampler)) => sampler.loop_mode = !sampler.loop_mode,
+        (Key::B, _) => card.bypassed = !card.bypassed,
+        (Key::N, CardClass::Noise(noise)) => {
+            noise.color = match noise.color {
+                NoiseColor::White => NoiseColor::Pink,
+                NoiseColor::Pink => NoiseColor::White,
+            }
+        }
+        _ => return false,
+    }
+    true
+}
+
+// Octave shift range for the computer keyboard, keeping its top note well under Nyquist
+const MIN_KEYBOARD_OCTAVE: i32 = -3;
+const MAX_KEYBOARD_OCTAVE: i32 = 3;
+
+// Piano-style layout on the home row, starting from middle C shifted by `octave`
+fn key_to_note(key: Key, octave: i32) -> Option<u8> {
+    let semitone = match key {
+        Key::A => 0,
+        Key::W => 1,
+        Key::S => 2,
+        Key::E => 3,
+        Key::D => 4,
+        Key::F => 5,
+        Key::T => 6,
+        Key::G => 7,
+        Key::Y => 8,
+        Key::H => 9,
+        Key::U => 10,
+        Key::J => 11,
+        Key::K => 12,
+        _ => return None,
+    };
+    Some((60 + octave * 12 + semitone) as u8)
+}
+
+fn midi_to_hz(note: u8) -> f64 {
+    note_to_hz(note, 440.0)
+}
+
+// Equal temperament with A4 (note 69) at `a4_hz`
+fn note_to_hz(note: u8, a4_hz: f64) -> f64 {
+    a4_hz * 2f64.powf((note as f64 - 69.0) / 12.0)
+}
+
+// Scientific pitch name, with middle C (note 60) as C4
+fn note_name(note: u8) -> String {
+    const NAMES: [&str; 12] = [
+        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    ];
+    let octave = note as i32 / 12 - 1;
+    format!("{}{}", NAMES[note as usize % 12], octave)
+}
+
+// Rounded to the step so repeated presses land exactly on fully dry and fully wet
+fn set_wet(model: &mut Model, wet: f32) {
+    model.wet = (wet.clamp(0.0, 1.0) * 10.0).round() / 10.0;
+    let wet = model.wet;
+    model
+        .stream
+        .send(move |audio| audio.wet_target = wet)
+        .unwrap();
+}
+
+fn set_width(model: &mut Model, width: f32) {
+    model.width = (width.clamp(0.0, MAX_WIDTH) * 10.0).round() / 10.0;
+    let width = model.width;
+    model
+        .stream
+        .send(move |audio| audio.width_target = width)
+        .unwrap();
+}
+
+fn set_master_volume(model: &mut Model, volume: f32) {
+    model.master_volume = volume.clamp(0.0, 1.0);
+    let volume = model.master_volume;
+    model
+        .stream
+        .send(move |audio| audio.master_volume = volume)
+        .unwrap();
+}
+
+// Soloing leaves each card's own bypass alone, so un-soloing puts the chain back as it was
+fn toggle_solo(model: &mut Model, index: usize) {
+    model.solo = if model.solo == Some(index) {
+        None
+    } else {
+        Some(index)
+    };
+    model.is_updating = true;
+}
+
+// Whether a solo silences this card. Sequencers and arpeggiators keep running, since they
+// play the notes the soloed card is heard through.
+fn muted_by_solo(model: &Model, index: usize) -> bool {
+    let note_source = matches!(
+        model.cards[index].class,
+        CardClass::Sequencer(_) | CardClass::Arpeggiator(_)
+    );
+    model.solo.is_some_and(|solo| solo != index) && !note_source
+}
+
+// Whether an arpeggiator in the chain is taking the held notes
+fn has_arpeggiator(model: &Model) -> bool {
+    model
+        .rows
+        .chain
+        .iter()
+        .chain(model.rows.branch.iter())
+        .any(|card| !card.bypassed && matches!(card.class, CardClass::Arpeggiator(_)))
+}
+
+// Each held key gets its own voice on the audio thread
+fn note_on(model: &mut Model, note: u8, velocity: f32) {
+    // Ignore key repeat while the note is already sounding
+    if model.held_notes.contains(&note) {
+        return;
+    }
+    model.held_notes.push(note);
+    if has_arpeggiator(model) {
+        return;
+    }
+    let hz = midi_to_hz(note);
+    model
+        .stream
+        .send(move |audio| audio.note_on(Some(note), hz, velocity, true))
+        .unwrap();
+    model.gate = true;
+}
+
+fn note_off(model: &mut Model, note: u8) {
+    model.held_notes.retain(|&held| held != note);
+    model
+        .stream
+        .send(move |audio| audio.note_off(note))
+        .unwrap();
+    model.gate = !model.held_notes.is_empty();
+}
+
+const PATCH_PATH: &str = "patch.json";
+
+// Everything needed to rebuild a session's layout and sound
+#[derive(Serialize, Deserialize)]
+struct Patch {
+    bpm: f32,
+    cards: Vec<Card>,
+    #[serde(default)]
+    seed: u64,
+    #[serde(default)]
+    routes: Vec<ModRoute>,
+}
+
+fn save_patch(model: &Model) {
+    let patch = Patch {
+        bpm: model.bpm,
+        cards: model.cards.clone(),
+        seed: model.seed,
+        routes: model.mod_routes.clone(),
+    };
+    let result = serde_json::to_string_pretty(&patch)
+        .map_err(|err| err.to_string())
+        .and_then(|json| std::fs::write(PATCH_PATH, json).map_err(|err| err.to_string()));
+    if let Err(err) = result {
+        eprintln!("Couldn't save {}: {}", PATCH_PATH, err);
+    }
+}
+
+fn load_patch(app: &App, model: &mut Model) {
+    if load_patch_file(app, model, Path::new(PATCH_PATH)) {
+        model.preset = None;
+    }
+}
+
+// Returns whether the patch loaded
+fn load_patch_file(app: &App, model: &mut Model, path: &Path) -> bool {
+    let result = std::fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|json| serde_json::from_str::<Patch>(&json).map_err(|err| err.to_string()));
+    match result {
+        Ok(patch) => {
+            push_history(model, snapshot(model));
+            set_bpm(model, patch.bpm);
+            model.cards = patch.cards;
+            for card in model.cards.iter_mut() {
+                if let CardClass::Sequencer(seq) = &mut card.class {
+                    seq.repair();
+                }
+            }
+            model.seed = patch.seed;
+            model.mod_routes = patch.routes;
+            model.rng = StdRng::seed_from_u64(patch.seed);
+            model.selected_card = None;
+            model.solo = None;
+            // Re-partition straight away so the audio reflects the restored layout
+            model.is_updating = true;
+            update_cards(app, model);
+            true
+        }
+        Err(err) => {
+            eprintln!("Couldn't load {}: {}", path.display(), err);
+            false
+        }
+    }
+}
+
+const PRESET_DIR: &str = "presets";
+
+// The `.json` patches in the preset folder, sorted by name. A missing folder just means
+// there are no presets.
+fn scan_presets() -> Vec<PathBuf> {
+    let entries = match std::fs::read_dir(PRESET_DIR) {
+        Ok(entries) => entries,
+        Err(err) => {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                eprintln!("Couldn't read {}: {}", PRESET_DIR, err);
+            }
+            return vec![];
+        }
+    };
+    let mut presets: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    presets.sort();
+    presets
+}
+
+// Records the output to a timestamped 32-bit float WAV file. The audio callback pushes
+// samples into a lock-free ring buffer that a writer thread drains to disk.
+fn start_recording(model: &mut Model) {
+    let config = model.stream.cpal_config();
+    let spec = hound::WavSpec {
+        channels: config.channels,
+        sample_rate: config.sample_rate.0,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    let path = format!("recording-{}.wav", timestamp);
+    let mut writer = match hound::WavWriter::create(&path, spec) {
+        Ok(writer) => writer,
+        Err(err) => {
+            eprintln!("Couldn't create {}: {}", path, err);
+            return;
+        }
+    };
+
+    // One second of headroom between the audio thread and the disk
+    let capacity = spec.sample_rate as usize * spec.channels as usize;
+    let (producer, mut consumer) = rtrb::RingBuffer::new(capacity);
+    std::thread::spawn(move || {
+        loop {
+            // Check before draining so samples pushed just before the producer is dropped
+            // still make it into the file
+            let finished = consumer.is_abandoned();
+            while let Ok(sample) = consumer.pop() {
+                if let Err(err) = writer.write_sample(sample) {
+                    eprintln!("Recording failed: {}", err);
+                    return;
+                }
+            }
+            if finished {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        if let Err(err) = writer.finalize() {
+            eprintln!("Couldn't finalize recording: {}", err);
+        }
+    });
+
+    model
+        .stream
+        .send(move |audio| audio.recorder = Some(producer))
+        .unwrap();
+    model.recording = true;
+}
+
+// Dropping the producer tells the writer thread to flush and finalize the WAV header
+fn stop_recording(model: &mut Model) {
+    model.stream.send(|audio| audio.recorder = None).unwrap();
+    model.recording = false;
+}
+
+// Copies the samples the audio thread has produced since the last frame
+fn update_scope(model: &mut Model) {
+    while let Ok(sample) = model.scope_consumer.pop() {
+        if model.scope_samples.len() == SCOPE_HISTORY {
+            model.scope_samples.pop_front();
+        }
+        model.scope_samples.push_back(sample);
+    }
+}
+
+// Runs a Hann-windowed FFT over a copy of the latest samples and folds the magnitudes
+// into log-spaced bars
+fn update_spectrum(model: &mut Model) {
+    let samples = &model.scope_samples;
+    if samples.len() < FFT_SIZE {
+        return;
+    }
+    let offset = samples.len() - FFT_SIZE;
+    for (i, bin) in model.fft_buffer.iter_mut().enumerate() {
+        let window = 0.5 - 0.5 * (2.0 * PI as f32 * i as f32 / (FFT_SIZE - 1) as f32).cos();
+        *bin = Complex::new(samples[offset + i] * window, 0.0);
+    }
+    model.fft.process(&mut model.fft_buffer);
+
+    let bins = FFT_SIZE / 2;
+    for (bar, level) in model.spectrum.iter_mut().enumerate() {
+        // Each bar covers an equal share of octaves, from bin 1 up to Nyquist
+        let lo = (bins as f32).powf(bar as f32 / SPECTRUM_BARS as f32) as usize;
+        let hi = ((bins as f32).powf((bar + 1) as f32 / SPECTRUM_BARS as f32) as usize).max(lo + 1);
+        let magnitude = model.fft_buffer[lo..hi.min(bins)]
+            .iter()
+            .map(|bin| bin.norm())
+            .fold(0.0, f32::max);
+        // Map -80..0 dB (relative to a full-scale sine) onto 0..1
+        let db = 20.0 * (magnitude / (FFT_SIZE as f32 / 4.0)).max(1e-6).log10();
+        let target = ((db + 80.0) / 80.0).clamp(0.0, 1.0);
+        // Rise immediately, fall back slowly
+        *level = if target > *level {
+            target
+        } else {
+            *level * 0.9 + target * 0.1
+        };
+    }
+}
+
+fn draw_spectrum(draw: &Draw, win: Rect, spectrum: &[f32]) {
+    let left = win.left() + 100.0;
+    let width = win.w() - 200.0;
+    let bottom = win.top() - 170.0;
+    let height = 60.0;
+    let bar_width = width / spectrum.len() as f32;
+    for (i, level) in spectrum.iter().enumerate() {
+        let h = level * height;
+        draw.rect()
+            .x_y(left + bar_width * (i as f32 + 0.5), bottom + h / 2.0)
+            .w_h(bar_width * 0.8, h)
+            .color(rgba(0.6, 0.8, 1.0, 0.6));
+    }
+}
+
+// Lists the card's parameters beside it, highlighting the focused row
+fn draw_inspector(draw: &Draw, card: &Card, row: usize) {
+    let mut class = card.class.clone();
+    let params = card_params(&mut class);
+    if params.is_empty() {
+        return;
+    }
+    let row = row.min(params.len() - 1);
+    // Long lists scroll to keep the focused row in view
+    let visible = params.len().min(INSPECTOR_ROWS);
+    let first = row
+        .saturating_sub(INSPECTOR_ROWS / 2)
+        .min(params.len() - visible);
+    let x = card.x + card.w / 2.0 + 80.0;
+    let top = card.y + card.h / 2.0 - 10.0;
+    let row_height = 18.0;
+    draw.rect()
+        .x_y(x, top - row_height * (visible as f32 - 1.0) / 2.0)
+        .w_h(140.0, row_height * visible as f32 + 8.0)
+        .color(rgba(0.0, 0.0, 0.0, 0.6));
+    for (i, param) in params.iter().enumerate().skip(first).take(visible) {
+        let color = if i == row { YELLOW } else { WHITE };
+        draw.text(&param.display())
+            .x_y(x, top - row_height * (i - first) as f32)
+            .w(130.0)
+            .left_justify()
+            .color(color)
+            .font_size(12);
+    }
+}
+
+// Reads the peak and load the audio thread has reported since the last frame
+fn update_meter(model: &mut Model, now: f32, dt: f32) {
+    let peak = f32::from_bits(model.peak.swap(0, Ordering::Relaxed));
+    if peak >= 1.0 {
+        model.clip_time = now;
+    }
+    model.meter_level = (model.meter_level - METER_DECAY * dt).max(peak);
+    let load = f32::from_bits(model.load.swap(0, Ordering::Relaxed));
+    model.cpu_load += (load - model.cpu_load) * CPU_LOAD_SMOOTHING;
+    let reduction = f32::from_bits(model.reduction.swap(0, Ordering::Relaxed));
+    model.reduction_level = (model.reduction_level - REDUCTION_DECAY * dt).max(reduction);
+    if reduction >= model.reduction_hold || now - model.reduction_hold_time > REDUCTION_HOLD_TIME {
+        model.reduction_hold = reduction;
+        model.reduction_hold_time = now;
+    }
+}
+
+// Gain reduction hanging down the right edge of a compressor card, with the deepest recent
+// reduction held as a line
+fn draw_reduction_meter(draw: &Draw, card: &Card, level: f32, hold: f32) {
+    let height = card.h * card.scale;
+    let x = card.x + card.w * card.scale / 2.0 + 8.0;
+    let top = card.y + height / 2.0;
+    let depth = |db: f32| (db / REDUCTION_RANGE).min(1.0) * height;
+    draw.rect()
+        .x_y(x, card.y)
+        .w_h(6.0, height)
+        .color(rgba(1.0, 1.0, 1.0, 0.2));
+    draw.rect()
+        .x_y(x, top - depth(level) / 2.0)
+        .w_h(6.0, depth(level))
+        .color(ORANGE);
+    if hold > 0.0 {
+        draw.rect()
+            .x_y(x, top - depth(hold))
+            .w_h(6.0, 2.0)
+            .color(WHITE);
+    }
+}
+
+// A lamp per beat of the bar left of the tempo, lighting the current one. The downbeat's
+// lamp is brighter to mark where the bar starts.
+fn draw_beat_lamps(draw: &Draw, win: Rect, beat: usize, beats: usize) {
+    let spacing = 12.0;
+    let right = win.right() - 130.0;
+    for i in 0..beats {
+        let lit = i == beat;
+        let color = match (lit, i == 0) {
+            (true, true) => rgba(1.0, 0.85, 0.2, 1.0),
+            (true, false) => rgba(0.5, 0.9, 0.3, 1.0),
+            (false, _) => rgba(1.0, 1.0, 1.0, 0.2),
+        };
+        draw.ellipse()
+            .x_y(right - (beats - 1 - i) as f32 * spacing, win.top() - 20.0)
+            .radius(4.0)
+            .color(color);
+    }
+}
+
+// Audio load readout under the tempo, red when the callback is close to running late
+fn draw_cpu_meter(draw: &Draw, win: Rect, load: f32) {
+    let x = win.right() - 80.0;
+    let y = win.top() - 40.0;
+    let width = 60.0;
+    let color = if load > CPU_LOAD_WARNING {
+        RED
+    } else {
+        LIMEGREEN
+    };
+    draw.rect()
+        .x_y(x, y)
+        .w_h(width, 6.0)
+        .color(rgba(1.0, 1.0, 1.0, 0.2));
+    let filled = width * load.min(1.0);
+    draw.rect()
+        .x_y(x - width / 2.0 + filled / 2.0, y)
+        .w_h(filled, 6.0)
+        .color(color);
+    draw.text(&format!("CPU {:.0}%", load * 100.0))
+        .x_y(x, y - 12.0)
+        .color(color)
+        .font_size(10);
+}
+
+const CABLE_DRAW_STEPS: usize = 24;
+// Cable lengths a pulse travels per second
+const CABLE_PULSE_SPEED: f32 = 1.5;
+
+// Patch cables between neighbouring cards in each row, in the order the audio runs them,
+// with the branch row's last card cabled into the mixer. Endpoints follow the cards as they
+// move. Cables touching a bypassed card are dashed and dim; the rest pulse while playing.
+fn draw_cables(draw: &Draw, model: &Model, time: f32) {
+    let row = |region: Region| {
+        let mut cards: Vec<&Card> = model
+            .cards
+            .iter()
+            .zip(&model.rows.regions)
+            .filter(|&(_, &r)| r == region)
+            .map(|(card, _)| card)
+            .collect();
+        cards.sort_by(|a, b| {
+            a.x_targ
+                .total_cmp(&b.x_targ)
+                .then(a.y_targ.total_cmp(&b.y_targ))
+        });
+        cards
+    };
+    let chain = row(Region::Chain);
+    let branch = row(Region::Branch);
+    let mut pairs: Vec<(&Card, &Card)> = chain
+        .windows(2)
+        .chain(branch.windows(2))
+        .map(|pair| (pair[0], pair[1]))
+        .collect();
+    let mixer = chain
+        .iter()
+        .find(|card| matches!(card.class, CardClass::Mixer(_)));
+    if let (Some(&last), Some(&mixer)) = (branch.last(), mixer) {
+        pairs.push((last, mixer));
+    }
+
+    for (from, to) in pairs {
+        let start = pt2(from.x + from.w / 2.0, from.y);
+        let end = pt2(to.x - to.w / 2.0, to.y);
+        let pull = ((end.x - start.x).abs() * 0.5).max(40.0);
+        let (c1, c2) = (start + vec2(pull, 0.0), end - vec2(pull, 0.0));
+        let bezier = |t: f32| {
+            let u = 1.0 - t;
+            start * u * u * u + c1 * 3.0 * u * u * t + c2 * 3.0 * u * t * t + end * t * t * t
+        };
+        let points: Vec<Point2> = (0..=CABLE_DRAW_STEPS)
+            .map(|i| bezier(i as f32 / CABLE_DRAW_STEPS as f32))
+            .collect();
+        if from.bypassed || to.bypassed {
+            for dash in points.windows(2).step_by(2) {
+                draw.line()
+                    .start(dash[0])
+                    .end(dash[1])
+                    .weight(2.0)
+                    .color(rgba(1.0, 1.0, 1.0, 0.25));
+            }
+        } else {
+            draw.polyline()
+                .weight(3.0)
+                .points(points)
+                .color(rgba(0.1, 0.1, 0.1, 0.8));
+        }
+        if model.playing && !from.bypassed && !to.bypassed {
+            draw.ellipse()
+                .xy(bezier((time * CABLE_PULSE_SPEED).fract()))
+                .radius(3.0)
+                .color(WHITE);
+        }
+    }
+}
+
+// Lists the modulation matrix under the CPU meter
+fn draw_routes(draw: &Draw, win: Rect, routes: &[ModRoute]) {
+    for (i, route) in routes.iter().enumerate() {
+        let text = format!(
+            "{}>{} {:+.1}",
+            route.source.label(),
+            route.dest.label(),
+            route.amount
+        );
+        draw.text(&text)
+            .x_y(win.right() - 80.0, win.top() - 70.0 - 14.0 * i as f32)
+            .color(WHITE)
+            .font_size(10);
+    }
+}
+
+// Peak meter beside the fader, lit red for a while after the output clips
+fn draw_meter(draw: &Draw, win: Rect, level: f32, clipping: bool) {
+    let x = win.right() - 20.0;
+    let height = 200.0;
+    let bottom = win.y() - height / 2.0;
+    let level = level.min(1.0);
+    draw.rect()
+        .x_y(x, win.y())
+        .w_h(8.0, height)
+        .color(rgba(1.0, 1.0, 1.0, 0.2));
+    draw.rect()
+        .x_y(x, bottom + level * height / 2.0)
+        .w_h(8.0, level * height)
+        .color(if clipping { RED } else { LIMEGREEN });
+    draw.rect()
+        .x_y(x, bottom + height + 8.0)
+        .w_h(8.0, 8.0)
+        .color(if clipping { RED } else { DIMGRAY });
+}
+
+// Cards are coloured by what they do: sources warm, modulators green, effects cool
+fn card_color(class: &CardClass) -> Rgb {
+    match class {
+        CardClass::Oscillator(_) => rgb(0.9, 0.6, 0.1),
+        CardClass::Noise(_) => rgb(0.75, 0.45, 0.2),
+        CardClass::Sampler(_) => rgb(0.8, 0.5, 0.35),
+        CardClass::Sequencer(_) => rgb(0.85, 0.3, 0.3),
+        CardClass::Arpeggiator(_) => rgb(0.85, 0.3, 0.55),
+        CardClass::Envelope(_) => rgb(0.2, 0.65, 0.3),
+        CardClass::Lfo(_) => rgb(0.45, 0.7, 0.2),
+        CardClass::Filter(_) => rgb(0.15, 0.35, 0.85),
+        CardClass::Delay(_) => rgb(0.5, 0.25, 0.75),
+        CardClass::Reverb(_) => rgb(0.35, 0.3, 0.7),
+        CardClass::Chorus(_) => rgb(0.2, 0.55, 0.7),
+        CardClass::Eq(_) => rgb(0.25, 0.45, 0.6),
+        CardClass::Compressor(_) => rgb(0.3, 0.35, 0.55),
+        CardClass::Bitcrush(_) => rgb(0.6, 0.6, 0.2),
+        CardClass::Vca(_) => rgb(0.4, 0.45, 0.5),
+        CardClass::Mixer(_) => rgb(0.45, 0.45, 0.5),
+    }
+}
+
+// Pulls a colour most of the way towards grey, for bypassed cards
+fn desaturate(color: Rgb) -> Rgb {
+    let luma = 0.3 * color.red + 0.59 * color.green + 0.11 * color.blue;
+    let mix = |c: f32| luma + (c - luma) * 0.3;
+    rgb(mix(color.red), mix(color.green), mix(color.blue))
+}
+
+// Draws the steps as bars in the lower part of a card, taller for higher notes, with the
+// step that last played highlighted. `draw` is centred on the card.
+fn draw_sequence(draw: &Draw, w: f32, h: f32, seq: &Sequencer) {
+    let len = seq.sequence.len();
+    if len == 0 {
+        return;
+    }
+    let lowest = *seq.sequence.iter().min().unwrap() as f32;
+    let highest = *seq.sequence.iter().max().unwrap() as f32;
+    let area_w = w * 0.8;
+    let area_h = h * 0.45;
+    let bottom = -h * 0.4;
+    let bar_w = area_w / len as f32;
+    let playing = (seq.step + len - 1) % len;
+    for (i, &note) in seq.sequence.iter().enumerate() {
+        // Flat patterns sit at half height rather than dividing by zero
+        let pitch = if highest > lowest {
+            (note as f32 - lowest) / (highest - lowest)
+        } else {
+            0.5
+        };
+        let bar_h = area_h * (0.2 + 0.8 * pitch);
+        let gate = seq.gates.get(i).copied().unwrap_or(true);
+        let alpha = if gate { 0.9 } else { 0.25 };
+        let color = if i == playing {
+            rgba(1.0, 1.0, 1.0, alpha)
+        } else {
+            rgba(0.0, 0.0, 0.0, alpha * 0.6)
+        };
+        draw.rect()
+            .x_y(
+                -area_w / 2.0 + bar_w * (i as f32 + 0.5),
+                bottom + bar_h / 2.0,
+            )
+            .w_h((bar_w * 0.8).max(1.0), bar_h)
+            .color(color);
+    }
+}
+
+// Draws the ADSR contour in the lower part of a card, with the sustain held for a fixed
+// share of the width. `draw` is centred on the card.
+fn draw_envelope(draw: &Draw, w: f32, h: f32, env: &Envelope) {
+    let area_w = w * 0.8;
+    let area_h = h * 0.45;
+    let left = -area_w / 2.0;
+    let bottom = -h * 0.4;
+    let sustain = env.sustain.clamp(0.0, 1.0);
+    let hold = 0.5;
+    // Stage lengths are floored so empty stages still leave a visible corner, and the
+    // total never reaches zero
+    let times = [env.attack, env.decay, env.release].map(|t| t.max(0.0) + 0.02);
+    let total = times.iter().sum::<f32>() + hold;
+    let x = |t: f32| left + area_w * t / total;
+    let y = |level: f32| bottom + area_h * level;
+    let release_start = times[0] + times[1] + hold;
+    // Run the audio thread's envelope at full velocity, holding the gate until the
+    // release, so the contour bends exactly as the sound does
+    let mut state = dsp::Envelope::new();
+    state.attack = times[0];
+    state.decay = times[1];
+    state.sustain = sustain;
+    state.release = times[2];
+    state.curve = env.curve;
+    state.velocity_amount = 0.0;
+    let dt = total / ENVELOPE_DRAW_STEPS as f32;
+    let samples = (1..=ENVELOPE_DRAW_STEPS).map(|i| {
+        let t = i as f32 * dt;
+        pt2(x(t), y(state.process(t <= release_start, dt)))
+    });
+    let segments = std::iter::once(pt2(x(0.0), y(0.0))).chain(samples);
+    draw.polyline().weight(2.0).points(segments).color(WHITE);
+}
+
+const ENVELOPE_DRAW_STEPS: usize = 64;
+
+// Short name drawn on a card and its palette button
+fn card_label(class: &CardClass) -> String {
+    match class {
+        CardClass::Sequencer(seq) if seq.target != SeqTarget::Pitch => {
+            format!("S:{} {}", seq.sequence.len(), seq.target.label())
+        }
+        CardClass::Sequencer(seq) => format!("S:{}", seq.sequence.len()),
+        CardClass::Oscillator(osc) if osc.modulator.is_some() => {
+            format!("FM:{}", osc.waveform.label())
+        }
+        CardClass::Oscillator(osc) => format!("O:{}", osc.waveform.label()),
+        CardClass::Envelope(env) => match env.curve {
+            EnvCurve::Linear => "E:Up".to_string(),
+            EnvCurve::Exponential => "E:Exp".to_string(),
+        },
+        CardClass::Delay(delay) => {
+            let mut text = match delay.sync {
+                Some(division) => format!("D:{}", division.label()),
+                None => "D".to_string(),
+            };
+            if delay.ping_pong {
+                text.push_str(" PP");
+            }
+            if delay.frozen {
+                text.push_str(" FRZ");
+            }
+            text
+        }
+        CardClass::Noise(_) => "N".to_string(),
+        CardClass::Sampler(sampler) if sampler.loop_mode => "Smp L".to_string(),
+        CardClass::Sampler(_) => "Smp".to_string(),
+        CardClass::Filter(filter) => format!("F:{}", filter.mode.label()),
+        CardClass::Lfo(lfo) => format!("L:{}", lfo.target.label()),
+        CardClass::Mixer(_) => "M".to_string(),
+        CardClass::Bitcrush(_) => "BC".to_string(),
+        CardClass::Vca(vca) => format!("x{:.2}", vca.gain),
+        CardClass::Reverb(_) => "R".to_string(),
+        CardClass::Chorus(chorus) => {
+            // With feedback the same card is heard as a flanger
+            if chorus.feedback > 0.0 {
+                "Fl".to_string()
+            } else {
+                "Ch".to_string()
+            }
+        }
+        CardClass::Arpeggiator(arp) => format!("A:{}", arp.mode.label()),
+        CardClass::Eq(_) => "EQ".to_string(),
+        CardClass::Compressor(_) => "Comp".to_string(),
+    }
+}
+
+// Palette buttons run down the left edge, one per card type
+fn palette_rect(win: Rect, index: usize) -> Rect {
+    Rect::from_x_y_w_h(
+        win.left() + 50.0,
+        win.top() - 70.0 - index as f32 * 28.0,
+        70.0,
+        24.0,
+    )
+}
+
+fn draw_palette(draw: &Draw, win: Rect, classes: &[CardClass]) {
+    for (i, class) in classes.iter().enumerate() {
+        let rect = palette_rect(win, i);
+        draw.rect()
+            .xy(rect.xy())
+            .wh(rect.wh())
+            .color(card_color(class));
+        draw.text(&card_label(class))
+            .xy(rect.xy())
+            .color(WHITE)
+            .font_size(12);
+    }
+}
+
+// Drop zone in the bottom-left corner that deletes cards
+fn trash_rect(win: Rect) -> Rect {
+    Rect::from_x_y_w_h(win.left() + 50.0, win.bottom() + 60.0, 70.0, 90.0)
 }
 
-fn key_pressed(_app: &App, model: &mut Model, key: Key) {
-    if key == Key::Space {
-        if model.stream.is_playing() {
-            model.stream.pause().unwrap();
+fn draw_trash(draw: &Draw, win: Rect, hovered: bool) {
+    let rect = trash_rect(win);
+    draw.rect()
+        .xy(rect.xy())
+        .wh(rect.wh())
+        .color(if hovered {
+            rgba(1.0, 0.0, 0.0, 0.4)
         } else {
-            model.stream.play().unwrap();
-        }
+            rgba(1.0, 1.0, 1.0, 0.1)
+        })
+        .stroke_weight(1.0)
+        .stroke(WHITE);
+    draw.text("BIN").xy(rect.xy()).color(WHITE).font_size(14);
+}
+
+// Vertical fader at the right edge showing the master volume
+fn draw_volume_fader(draw: &Draw, win: Rect, volume: f32) {
+    let x = win.right() - 40.0;
+    let height = 200.0;
+    let bottom = win.y() - height / 2.0;
+    draw.rect()
+        .x_y(x, win.y())
+        .w_h(8.0, height)
+        .color(rgba(1.0, 1.0, 1.0, 0.2));
+    draw.rect()
+        .x_y(x, bottom + volume * height / 2.0)
+        .w_h(8.0, volume * height)
+        .color(rgba(0.6, 0.8, 1.0, 0.6));
+    draw.rect()
+        .x_y(x, bottom + volume * height)
+        .w_h(24.0, 6.0)
+        .color(WHITE);
+    draw.text("VOL")
+        .x_y(x, bottom - 16.0)
+        .color(WHITE)
+        .font_size(12);
+}
+
+// Draws the output waveform across the top of the window, starting from a rising zero
+// crossing so the trace stands still instead of scrolling
+fn draw_scope(draw: &Draw, win: Rect, samples: &VecDeque<f32>) {
+    if samples.len() < SCOPE_WIDTH * 2 {
+        return;
     }
+    let search_end = samples.len() - SCOPE_WIDTH;
+    let start = (1..search_end)
+        .find(|&i| samples[i - 1] < 0.0 && samples[i] >= 0.0)
+        .unwrap_or(search_end);
+
+    let left = win.left() + 100.0;
+    let width = win.w() - 200.0;
+    let center_y = win.top() - 50.0;
+    let height = 40.0;
+    let points = (0..SCOPE_WIDTH).map(|i| {
+        let x = left + width * i as f32 / (SCOPE_WIDTH - 1) as f32;
+        let y = center_y + samples[start + i].clamp(-1.0, 1.0) * height;
+        pt2(x, y)
+    });
+    draw.polyline()
+        .weight(1.5)
+        .points(points)
+        .color(rgba(0.6, 1.0, 0.6, 0.8));
 }
 
 fn view(app: &App, model: &Model, frame: Frame) {
     let draw = app.draw();
     draw.background().color(DARKSLATEGRAY);
 
+    let slot_scale = model.grid_size / GRID_SIZE;
     for slot in &model.grid_slots {
         draw.rect()
             .x_y(slot.x, slot.y)
-            .w_h(110.0, 150.0)
+            .w_h(110.0 * slot_scale, 150.0 * slot_scale)
             .color(Rgba::new(1.0, 1.0, 1.0, 0.2))
             .stroke_weight(0.4);
         draw.rect()
             .x_y(slot.x, slot.y)
-            .w_h(100.0, 140.0)
+            .w_h(CARD_WIDTH * slot_scale, CARD_HEIGHT * slot_scale)
             .color(Rgba::new(1.0, 1.0, 1.0, 0.2))
             .stroke_weight(0.2)
             .stroke(BLACK);
     }
 
-    for card in model.cards.iter() {
+    let win = app.window_rect();
+    let dragging = model.selected_card.is_some_and(|i| model.cards[i].dragging);
+    draw_trash(
+        &draw,
+        win,
+        dragging && trash_rect(win).contains(app.mouse.position()),
+    );
+    draw_palette(&draw, win, &model.palette);
+    draw_cables(&draw, model, app.time);
+
+    for (i, card) in model.cards.iter().enumerate() {
         if card.dragging {
             draw.rect()
                 .x_y(card.x * 0.9, card.y - 15.0)
@@ -269,19 +4932,219 @@ fn view(app: &App, model: &Model, frame: Frame) {
             .x_y(card.x, card.y)
             .w_h(card.w * card.scale, card.h * card.scale)
             .rotate(card.rotation)
-            .color(BLUE);
+            .color(if card.bypassed {
+                let color = desaturate(card_color(&card.class));
+                rgba(color.red, color.green, color.blue, 0.35)
+            } else {
+                let color = card_color(&card.class);
+                rgba(color.red, color.green, color.blue, 1.0)
+            });
+        if model.solo == Some(i) {
+            draw.rect()
+                .x_y(card.x, card.y)
+                .w_h(card.w * card.scale + 6.0, card.h * card.scale + 6.0)
+                .rotate(card.rotation)
+                .no_fill()
+                .stroke(GOLD)
+                .stroke_weight(3.0);
+        }
 
-        let text = match card.class {
-            CardClass::Sequencer(_) => "S",
-            CardClass::Oscillator(_) => "O",
-            CardClass::Envelope(_) => "E:Up",
-            CardClass::Delay(_) => "D",
-        };
+        let text = card_label(&card.class);
 
-        draw.text(text)
+        // Sequencers play from the chain's copy, which holds the live step. A copy of
+        // another kind is one a quantized change is still holding back.
+        let live = model
+            .rows
+            .copy(i)
+            .filter(|copy| {
+                std::mem::discriminant(&copy.class) == std::mem::discriminant(&card.class)
+            })
+            .unwrap_or(card);
+        let face = draw
             .x_y(card.x, card.y)
+            .rotate(card.rotation)
+            .scale(card.scale);
+        match &live.class {
+            CardClass::Sequencer(seq) => {
+                face.text(&text)
+                    .x_y(0.0, card.h * 0.3)
+                    .color(WHITE)
+                    .font_size(24);
+                draw_sequence(&face, card.w, card.h, seq);
+            }
+            CardClass::Envelope(env) => {
+                face.text(&text)
+                    .x_y(0.0, card.h * 0.3)
+                    .color(WHITE)
+                    .font_size(24);
+                draw_envelope(&face, card.w, card.h, env);
+            }
+            _ => {
+                draw.text(&text)
+                    .x_y(card.x, card.y)
+                    .color(WHITE)
+                    .font_size(32);
+            }
+        }
+    }
+
+    draw_scope(&draw, app.window_rect(), &model.scope_samples);
+    draw_spectrum(&draw, app.window_rect(), &model.spectrum);
+    draw_volume_fader(&draw, app.window_rect(), model.master_volume);
+    let clipping = app.time - model.clip_time < CLIP_HOLD_TIME;
+    draw_meter(&draw, app.window_rect(), model.meter_level, clipping);
+    draw_cpu_meter(&draw, app.window_rect(), model.cpu_load);
+    draw_routes(&draw, app.window_rect(), &model.mod_routes);
+    if let Some(i) = model.output_device {
+        let win = app.window_rect();
+        draw.text(&format!("OUT {}", model.output_devices[i]))
+            .x_y(win.x(), win.bottom() + 12.0)
+            .w(win.w())
+            .color(WHITE)
+            .font_size(12);
+    }
+    // Only the first compressor in the chain runs, so only it gets a meter
+    let compressor = model
+        .cards
+        .iter()
+        .filter(|card| matches!(card.class, CardClass::Compressor(_)) && !card.bypassed)
+        .map(|card| (card_region(card, app.window_rect()), card))
+        .filter(|&(region, _)| region != Region::Hand)
+        .min_by(|(a, card_a), (b, card_b)| {
+            (*a == Region::Branch)
+                .cmp(&(*b == Region::Branch))
+                .then(card_a.x_targ.total_cmp(&card_b.x_targ))
+                .then(card_a.y_targ.total_cmp(&card_b.y_targ))
+        })
+        .map(|(_, card)| card);
+    if let Some(card) = compressor {
+        draw_reduction_meter(&draw, card, model.reduction_level, model.reduction_hold);
+    }
+
+    if let Some(selected) = model.selected_card {
+        draw_inspector(&draw, &model.cards[selected], model.inspector_row);
+    }
+
+    if model.recording {
+        let win = app.window_rect();
+        draw.text("REC")
+            .x_y(win.left() + 40.0, win.top() - 20.0)
+            .color(RED)
+            .font_size(18);
+    }
+
+    if model.clock_source == ClockSource::Midi {
+        let win = app.window_rect();
+        draw.text("MIDI CLK")
+            .x_y(win.left() + 120.0, win.top() - 20.0)
+            .color(WHITE)
+            .font_size(14);
+    }
+
+    let win = app.window_rect();
+    draw.text(&format!("{:.0} BPM", model.tempo))
+        .x_y(win.right() - 80.0, win.top() - 20.0)
+        .color(WHITE)
+        .font_size(14);
+    // Stopping rewinds the beat clock, so a stopped transport shows the first beat
+    let beat = model.beat_step % model.beats_per_bar;
+    draw_beat_lamps(&draw, win, beat, model.beats_per_bar);
+
+    if model.snapshot_a.is_some() && model.snapshot_b.is_some() {
+        let win = app.window_rect();
+        draw.text(&format!("A>B {:.1}", model.morph))
+            .x_y(win.left() + 470.0, win.top() - 20.0)
+            .color(WHITE)
+            .font_size(14);
+    }
+
+    if model.wet < 1.0 {
+        let win = app.window_rect();
+        draw.text(&format!("WET {:.1}", model.wet))
+            .x_y(win.left() + 630.0, win.top() - 20.0)
+            .color(WHITE)
+            .font_size(14);
+    }
+
+    if model.width != 1.0 {
+        let win = app.window_rect();
+        draw.text(&format!("WIDTH {:.1}", model.width))
+            .x_y(win.left() + 710.0, win.top() - 20.0)
+            .color(WHITE)
+            .font_size(14);
+    }
+
+    if model.keyboard_octave != 0 {
+        let win = app.window_rect();
+        draw.text(&format!("OCT {:+}", model.keyboard_octave))
+            .x_y(win.left() + 790.0, win.top() - 20.0)
+            .color(WHITE)
+            .font_size(14);
+    }
+
+    if model.quantize {
+        let win = app.window_rect();
+        draw.text("QUANT")
+            .x_y(win.left() + 870.0, win.top() - 20.0)
+            .color(if model.pending_drop { YELLOW } else { WHITE })
+            .font_size(14);
+    }
+
+    if model.listening {
+        let win = app.window_rect();
+        draw.text("MIC")
+            .x_y(win.left() + 550.0, win.top() - 20.0)
+            .color(if model.onset { RED } else { WHITE })
+            .font_size(14);
+    }
+
+    if model.humanize > 0.0 {
+        let win = app.window_rect();
+        draw.text(&format!("HUMAN {:.1}", model.humanize))
+            .x_y(win.left() + 120.0, win.top() - 40.0)
+            .color(WHITE)
+            .font_size(14);
+    }
+
+    if model.latch {
+        let win = app.window_rect();
+        draw.text("LATCH")
+            .x_y(win.left() + 40.0, win.top() - 40.0)
+            .color(WHITE)
+            .font_size(14);
+    }
+
+    if let Some(i) = model.preset {
+        let win = app.window_rect();
+        let name = model.presets[i].file_stem().unwrap_or_default();
+        draw.text(&name.to_string_lossy())
+            .x_y(win.left() + 260.0, win.top() - 40.0)
+            .color(WHITE)
+            .font_size(14);
+    }
+
+    if !model.playing {
+        let win = app.window_rect();
+        draw.text("STOP")
+            .x_y(win.left() + 390.0, win.top() - 20.0)
+            .color(WHITE)
+            .font_size(14);
+    }
+
+    if model.metronome_on {
+        let win = app.window_rect();
+        draw.text("MET")
+            .x_y(win.left() + 310.0, win.top() - 20.0)
+            .color(WHITE)
+            .font_size(14);
+    }
+
+    if model.glide && model.glide_time > 0.0 {
+        let win = app.window_rect();
+        draw.text(&format!("GLIDE {:.2}s", model.glide_time))
+            .x_y(win.left() + 220.0, win.top() - 20.0)
             .color(WHITE)
-            .font_size(32);
+            .font_size(14);
     }
 
     draw.to_frame(app, &frame).unwrap();
@@ -295,17 +5158,12 @@ fn remove_card_from_collections(model: &mut Model, card_index: usize) {
     {
         model.hand.remove(pos);
     }
-    if let Some(pos) = model
-        .chain
-        .iter()
-        .position(|c| c == &model.cards[card_index])
-    {
-        model.chain.remove(pos);
-    }
+    model.rows.remove(card_index);
 }
 
 fn mouse_pressed(app: &App, model: &mut Model, _button: MouseButton) {
-    if model.selected_card.is_none() {
+    // A cutoff sweep has the mouse until Ctrl is let go
+    if model.selected_card.is_none() && model.sweep.is_none() {
         let x = app.mouse.x;
         let y = app.mouse.y;
         model.is_mouse_pressed = true;
@@ -317,30 +5175,265 @@ fn mouse_pressed(app: &App, model: &mut Model, _button: MouseButton) {
             {
                 card.dragging = true;
                 model.selected_card = Some(i);
+                model.inspector_row = 0;
+                model.drag_origin = pt2(card.x_targ, card.y_targ);
                 card.start_time = app.time;
-                remove_card_from_collections(model, i);
+                if !(model.quantize && model.playing) {
+                    remove_card_from_collections(model, i);
+                }
                 model.is_updating = true;
                 break;
             }
         }
+        if model.selected_card.is_none() {
+            // A palette click deals a card into the hand, so nothing joins the audio until
+            // it is dragged into a row. Cards are hit first since they are drawn over the
+            // palette.
+            let win = app.window_rect();
+            let button =
+                (0..model.palette.len()).find(|&i| palette_rect(win, i).contains(pt2(x, y)));
+            if let Some(i) = button {
+                let before = snapshot(model);
+                let hand_y = model
+                    .grid_slots
+                    .iter()
+                    .map(|slot| slot.y)
+                    .reduce(f32::min)
+                    .unwrap_or(y);
+                let mut card = Card::new(x, hand_y, model.palette[i].clone());
+                card.start_time = app.time;
+                card.w = CARD_WIDTH * model.grid_size / GRID_SIZE;
+                card.h = CARD_HEIGHT * model.grid_size / GRID_SIZE;
+                model.cards.push(card);
+                model.is_updating = true;
+                push_history(model, before);
+            }
+        }
     }
 }
 
-fn mouse_released(_app: &App, model: &mut Model, _button: MouseButton) {
+fn mouse_released(app: &App, model: &mut Model, _button: MouseButton) {
     model.is_mouse_pressed = false;
     if let Some(selected) = model.selected_card {
+        // The card still moves now, but the audio hears the change on the next beat. Later
+        // drops in the same beat join the one already waiting.
+        if model.quantize && model.playing && model.cards[selected].dragging {
+            model.pending_drop = true;
+        }
+        // Dropped on the bin: the card leaves the patch. It was already taken out of the
+        // hand and chain when picked up.
+        if model.cards[selected].dragging
+            && trash_rect(app.window_rect()).contains(app.mouse.position())
+        {
+            let before = snapshot(model);
+            model.cards.remove(selected);
+            model.selected_card = None;
+            model.solo = match model.solo {
+                Some(solo) if solo == selected => None,
+                Some(solo) if solo > selected => Some(solo - 1),
+                solo => solo,
+            };
+            model.is_updating = true;
+            push_history(model, before);
+            return;
+        }
+        let occupied: Vec<Point2> = model
+            .cards
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != selected)
+            .map(|(_, card)| pt2(card.x_targ, card.y_targ))
+            .collect();
+        let before = snapshot(model);
+        if model.cards[selected].dragging && insert_into_row(app, model, selected) {
+            model.cards[selected].dragging = false;
+            model.is_updating = true;
+            push_history(model, before);
+            model.selected_card = None;
+            return;
+        }
         let card = &mut model.cards[selected];
         if card.dragging {
-            let (new_x, new_y) = snap_to_grid(card.x_targ, card.y_targ, &model.grid_slots);
+            // With every slot taken the card goes back to where it was picked up
+            let (new_x, new_y) =
+                snap_to_grid(card.x_targ, card.y_targ, &model.grid_slots, &occupied)
+                    .unwrap_or((model.drag_origin.x, model.drag_origin.y));
             card.x_targ = new_x;
             card.y_targ = new_y;
             card.dragging = false;
             model.is_updating = true;
+            if pt2(new_x, new_y) != model.drag_origin {
+                push_history(model, before);
+            }
         }
         model.selected_card = None;
     }
 }
 
+// Dropping a card on a taken slot in the chain or branch row puts it there, moving that
+// card and its neighbours one slot right into the first gap. If the row is full up to its
+// end, the last card is bumped into the hand. Returns false when the slot was free.
+fn insert_into_row(app: &App, model: &mut Model, selected: usize) -> bool {
+    let win = app.window_rect();
+    let card = &model.cards[selected];
+    let dist = |slot: &Point2| distance(card.x_targ, card.y_targ, slot.x, slot.y);
+    let Some(&target) = model
+        .grid_slots
+        .iter()
+        .min_by(|a, b| dist(a).total_cmp(&dist(b)))
+    else {
+        return false;
+    };
+    if region_at(target.y, win) == Region::Hand {
+        return false;
+    }
+    let card_at = |slot: Point2| {
+        (0..model.cards.len()).find(|&i| {
+            let card = &model.cards[i];
+            i != selected && distance(card.x_targ, card.y_targ, slot.x, slot.y) < 1.0
+        })
+    };
+    if card_at(target).is_none() {
+        return false;
+    }
+    let row: Vec<Point2> = model
+        .grid_slots
+        .iter()
+        .copied()
+        .filter(|slot| slot.y == target.y && slot.x >= target.x)
+        .collect();
+    let mut shifted = vec![];
+    for &slot in row.iter() {
+        match card_at(slot) {
+            Some(i) => shifted.push(i),
+            None => break,
+        }
+    }
+    let hand_y = model
+        .grid_slots
+        .iter()
+        .map(|slot| slot.y)
+        .reduce(f32::min)
+        .unwrap();
+    for (column, &i) in shifted.iter().enumerate() {
+        let card = &mut model.cards[i];
+        match row.get(column + 1) {
+            Some(slot) => card.x_targ = slot.x,
+            None => card.y_targ = hand_y,
+        }
+    }
+    let card = &mut model.cards[selected];
+    card.x_targ = target.x;
+    card.y_targ = target.y;
+    true
+}
+
+// Scrolling over a card edits its main parameter, or its second one with Shift held
+fn mouse_wheel(app: &App, model: &mut Model, delta: MouseScrollDelta, _phase: TouchPhase) {
+    let steps = match delta {
+        MouseScrollDelta::LineDelta(_, y) => y,
+        MouseScrollDelta::PixelDelta(position) => position.y as f32 / 20.0,
+    };
+    let (x, y) = (app.mouse.x, app.mouse.y);
+    let hovered = model.cards.iter().position(|card| {
+        x >= card.x - card.w / 2.0
+            && x <= card.x + card.w / 2.0
+            && y >= card.y - card.h / 2.0
+            && y <= card.y + card.h / 2.0
+    });
+    if let Some(index) = hovered {
+        let before = snapshot(model);
+        let card = &mut model.cards[index];
+        let [primary, secondary] = wheel_rows(&card.class);
+        let row = if app.keys.mods.shift() {
+            secondary
+        } else {
+            primary
+        };
+        if let Some(param) = card_params(&mut card.class).get_mut(row) {
+            param.nudge(steps);
+        }
+        if model.cards[index].class != before[index].class {
+            // A run of ticks on one card is a single undo step
+            let joins = model
+                .wheel
+                .is_some_and(|(card, time)| card == index && app.time - time < WHEEL_EDIT_GAP);
+            if !joins {
+                push_history(model, before);
+            }
+            model.wheel = Some((index, app.time));
+            sync_copy(model, index);
+        }
+    }
+}
+
+// Inspector rows edited by the mouse wheel, without and with Shift
+fn wheel_rows(class: &CardClass) -> [usize; 2] {
+    match class {
+        CardClass::Delay(_) => [1, 2],
+        _ => [0, 1],
+    }
+}
+
+// A filter cutoff sweep played like a knob: Ctrl held over a filter card, then moved
+// sideways
+struct Sweep {
+    card: usize,
+    last_x: f32,
+    before: Vec<Card>, // Cards as they were when the sweep began, for undo
+}
+
+// Pixels of horizontal mouse travel per octave of cutoff, so the sweep is even across the
+// range
+const SWEEP_PIXELS_PER_OCTAVE: f32 = 60.0;
+const MIN_SWEEP_CUTOFF: f32 = 20.0;
+const MAX_SWEEP_CUTOFF: f32 = 20000.0;
+
+fn handle_sweep(app: &App, model: &mut Model) {
+    let x = app.mouse.x;
+    // Never starts during a drag, and ends, recording one undo step, when Ctrl is let go
+    if !app.keys.mods.ctrl() || model.is_mouse_pressed {
+        if let Some(sweep) = model.sweep.take() {
+            if model.cards.get(sweep.card).map(|card| &card.class)
+                != sweep.before.get(sweep.card).map(|card| &card.class)
+            {
+                push_history(model, sweep.before);
+            }
+        }
+        return;
+    }
+    let Some(sweep) = &mut model.sweep else {
+        let y = app.mouse.y;
+        let hovered = model.cards.iter().position(|card| {
+            matches!(card.class, CardClass::Filter(_))
+                && x >= card.x - card.w / 2.0
+                && x <= card.x + card.w / 2.0
+                && y >= card.y - card.h / 2.0
+                && y <= card.y + card.h / 2.0
+        });
+        if let Some(card) = hovered {
+            model.sweep = Some(Sweep {
+                card,
+                last_x: x,
+                before: snapshot(model),
+            });
+        }
+        return;
+    };
+    let delta = x - sweep.last_x;
+    sweep.last_x = x;
+    if delta == 0.0 {
+        return;
+    }
+    let index = sweep.card;
+    if let Some(CardClass::Filter(filter)) = model.cards.get_mut(index).map(|c| &mut c.class) {
+        let cutoff = filter.cutoff * (delta / SWEEP_PIXELS_PER_OCTAVE).exp2();
+        filter.cutoff = cutoff.clamp(MIN_SWEEP_CUTOFF, MAX_SWEEP_CUTOFF);
+        // Only the cutoff moved, so the rows stay as they are
+        sync_copy(model, index);
+    }
+}
+
 fn handle_drag(app: &App, model: &mut Model) {
     if let Some(selected) = model.selected_card {
         let card = &mut model.cards[selected];
@@ -351,7 +5444,6 @@ fn handle_drag(app: &App, model: &mut Model) {
         if model.is_mouse_pressed && card.dragging {
             card.x_targ = x;
             card.y_targ = y;
-            model.is_updating = true;
         } else {
             card.x_targ = card.x_last;
             card.y_targ = card.y_last;
@@ -359,38 +5451,535 @@ fn handle_drag(app: &App, model: &mut Model) {
     }
 }
 
-fn update(app: &App, model: &mut Model, _update: Update) {
-    let now = app.time;
-    let time_since_last_update = now - model.last_update;
-    let beat_duration = 60.0 / model.bpm;
-
-    model.beat_time += time_since_last_update as f32;
-
-    if model.beat_time >= beat_duration {
-        model.beat_time = 0.0;
+// Holding Alt bends every voice by the mouse height; letting go returns to zero
+fn update_pitch_bend(app: &App, model: &mut Model) {
+    let bend = if app.keys.mods.alt() {
+        let half_height = app.window_rect().h() / 2.0;
+        (app.mouse.y / half_height).clamp(-1.0, 1.0) * PITCH_BEND_RANGE
+    } else {
+        0.0
+    };
+    if bend != model.pitch_bend {
+        model.pitch_bend = bend;
+        model
+            .stream
+            .send(move |audio| audio.pitch_bend_target = bend)
+            .unwrap();
+    }
+}
+
+// The time into the next step once the beat clock has run past the end of this one, or
+// `None` while the step is still going. The overshoot carries over so beats don't drift
+// with the frame rate.
+fn cross_beat(beat_time: f32, step_duration: f32) -> Option<f32> {
+    (beat_time >= step_duration).then(|| (beat_time - step_duration).min(step_duration))
+}
+
+fn update(app: &App, model: &mut Model, _update: Update) {
+    let now = app.time;
+    let time_since_last_update = now - model.last_update;
+    let restarted = std::mem::take(&mut model.restart);
+    let started = handle_midi(model, now) || restarted;
+    handle_osc(model);
+    if let Some((start, swap)) = model.swap {
+        if now - start >= SWAP_FADE_TIME {
+            finish_swap(app, model, swap);
+        }
+    }
+
+    // MIDI clock only counts while pulses keep arriving, so unplugged gear can't freeze the beat
+    let synced = model.clock_source == ClockSource::Midi
+        && model.pulse_interval > 0.0
+        && now - model.last_clock_time < CLOCK_TIMEOUT;
+    let tempo = if synced {
+        60.0 / (model.pulse_interval * CLOCK_PULSES_PER_BEAT)
+    } else {
+        model.bpm
+    };
+    if (tempo - model.tempo).abs() > 0.5 {
+        model.tempo = tempo;
+    }
+
+    let beat_duration = 60.0 / tempo;
+    // Swing lengthens even steps and shortens odd ones by the same amount, so off-beats
+    // land late while each pair of steps still spans two beats
+    let swung = |step: usize| {
+        if step.is_multiple_of(2) {
+            beat_duration * (1.0 + model.swing)
+        } else {
+            beat_duration * (1.0 - model.swing)
+        }
+    };
+    let step_duration = swung(model.beat_step);
+
+    // Stopped external gear holds the beat where it is
+    model.beat_delta = 0.0;
+    if model.playing && (!synced || model.midi_running) {
+        model.beat_time += time_since_last_update;
+        model.beat_delta = time_since_last_update / beat_duration;
+    }
+
+    // Edge-triggered rather than comparing `beat_time` to zero, which depends on frame timing
+    let carried = cross_beat(model.beat_time, step_duration);
+    model.beat_crossed = started || carried.is_some();
+    if started {
+        model.beat_step = 0;
+    } else if let Some(beat_time) = carried {
+        model.beat_time = beat_time;
+        model.beat_step = model.beat_step.wrapping_add(1);
+    }
+    model.step_duration = swung(model.beat_step);
+    if model.pending_drop && model.beat_crossed {
+        model.pending_drop = false;
+        model.is_updating = true;
+    }
+
+    model.last_update = now;
+
+    update_scope(model);
+    update_spectrum(model);
+    update_meter(model, now, time_since_last_update);
+    handle_drag(app, model);
+    handle_sweep(app, model);
+    update_pitch_bend(app, model);
+    update_cards(app, model);
+    animations(app, model);
+    lerp(model);
+    update_onsets(model);
+    update_sound(app, model);
+}
+
+const ONSET_QUEUE: usize = 16;
+// Time constants of the short and long input energy averages, in seconds
+const ONSET_FAST_TIME: f32 = 0.002;
+const ONSET_SLOW_TIME: f32 = 0.25;
+// An onset is the short average jumping this far over the long one...
+const ONSET_RATIO: f32 = 8.0;
+// ...while loud enough not to be room noise
+const ONSET_MIN_ENERGY: f32 = 0.001;
+// Quiet time after an onset, so one clap fires once
+const ONSET_DEBOUNCE_TIME: f32 = 0.15;
+
+// Input-thread state for spotting transients in the microphone signal
+struct OnsetDetector {
+    fast: f32, // Short average of the input energy
+    slow: f32, // Long average, the background level
+    hold: u32, // Samples left before another onset can fire
+    onsets: rtrb::Producer<()>,
+}
+
+impl OnsetDetector {
+    fn new(onsets: rtrb::Producer<()>) -> Self {
+        OnsetDetector {
+            fast: 0.0,
+            slow: 0.0,
+            hold: 0,
+            onsets,
+        }
+    }
+}
+
+fn detect_onsets(detector: &mut OnsetDetector, buffer: &Buffer) {
+    let sample_rate = buffer.sample_rate() as f32;
+    let fast = 1.0 - (-1.0 / (ONSET_FAST_TIME * sample_rate)).exp();
+    let slow = 1.0 - (-1.0 / (ONSET_SLOW_TIME * sample_rate)).exp();
+    let channels = buffer.channels().max(1) as f32;
+    for frame in buffer.frames() {
+        let energy = frame.iter().map(|x| x * x).sum::<f32>() / channels;
+        detector.fast += (energy - detector.fast) * fast;
+        detector.slow += (energy - detector.slow) * slow;
+        detector.hold = detector.hold.saturating_sub(1);
+        if detector.hold == 0
+            && detector.fast > ONSET_MIN_ENERGY
+            && detector.fast > detector.slow * ONSET_RATIO
+        {
+            // Dropped if the UI hasn't collected the last few
+            let _ = detector.onsets.push(());
+            detector.hold = (ONSET_DEBOUNCE_TIME * sample_rate) as u32;
+        }
+    }
+}
+
+// F10 starts or stops listening to the microphone for onsets
+fn toggle_listening(model: &mut Model) {
+    let Some(mic) = &model.mic else {
+        eprintln!("No microphone for onset triggers");
+        return;
+    };
+    model.listening = !model.listening;
+    if model.listening {
+        mic.play().unwrap();
+    } else {
+        mic.pause().unwrap();
+    }
+}
+
+// Collects the onsets heard since the last frame, which count as a single trigger
+fn update_onsets(model: &mut Model) {
+    model.onset = false;
+    while model.onsets.pop().is_ok() {
+        model.onset = model.listening;
+    }
+}
+
+const EVENT_QUEUE: usize = 256;
+// How far ahead of the audio thread notes are scheduled, enough to cover a buffer and a
+// late frame, in seconds
+const EVENT_LATENCY: f32 = 0.05;
+
+// A one-shot note for the audio thread to play at a given output frame
+#[derive(Clone, Copy, Debug)]
+struct TimedEvent {
+    frame: u64,
+    kind: NoteEvent,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum NoteEvent {
+    On { hz: f64, velocity: f32 },
+    Retrigger, // Plays the free-running pitch
+    Click { accent: bool },
+}
+
+// Frames rendered at the start of the latest buffer, and when that was in nanoseconds
+// since the shared epoch
+struct AudioClock {
+    frames: AtomicU64,
+    nanos: AtomicU64,
+}
+
+// Longest a humanized note is held back, at full humanize and as a share of the time to the
+// next note, and the most its velocity is lowered
+const HUMANIZE_MAX_DELAY: f32 = 0.03;
+const HUMANIZE_MAX_SHARE: f32 = 0.25;
+const HUMANIZE_VELOCITY: f32 = 0.3;
+
+// A random delay in seconds and velocity for a triggered note, scaled by the humanize
+// amount. Notes only ever land late, by less than the gap to the next one.
+fn humanize(model: &mut Model, spacing: f32) -> (f32, f32) {
+    if model.humanize <= 0.0 {
+        return (0.0, 1.0);
+    }
+    let limit = HUMANIZE_MAX_DELAY.min(spacing * HUMANIZE_MAX_SHARE);
+    let delay = model.humanize_rng.gen::<f32>() * model.humanize * limit;
+    let velocity = 1.0 - model.humanize_rng.gen::<f32>() * model.humanize * HUMANIZE_VELOCITY;
+    (delay, velocity)
+}
+
+// Queues a note to sound `ago` seconds before now, plus the scheduling latency. The audio
+// clock is read forward from its last buffer, so the gap between notes survives frame jitter.
+fn schedule(model: &mut Model, kind: NoteEvent, ago: f32) {
+    let sample_rate = model.stream.cpal_config().sample_rate.0 as f64;
+    let frames = model.clock.frames.load(Ordering::Relaxed);
+    let published = Duration::from_nanos(model.clock.nanos.load(Ordering::Relaxed));
+    let since = model.clock_epoch.elapsed().saturating_sub(published);
+    let offset = since.as_secs_f64() + (EVENT_LATENCY - ago) as f64;
+    let frame = event_frame(frames, offset, sample_rate);
+    // A full queue drops the note rather than blocking
+    let _ = model.events.push(TimedEvent { frame, kind });
+}
+
+// The output frame `offset` seconds after frame `frames`, never before the stream started
+fn event_frame(frames: u64, offset: f64, sample_rate: f64) -> u64 {
+    (frames as f64 + offset * sample_rate).round().max(0.0) as u64
+}
+
+// Takes the next queued event if it's due by `frame_clock`
+fn next_due(events: &mut rtrb::Consumer<TimedEvent>, frame_clock: u64) -> Option<NoteEvent> {
+    let due = events.peek().is_ok_and(|event| event.frame <= frame_clock);
+    due.then(|| events.pop().unwrap().kind)
+}
+
+// Puts the beat clock, sequencers and arpeggiators back at the top of the pattern
+fn rewind(model: &mut Model) {
+    model.beat_time = 0.0;
+    model.beat_step = 0;
+    for card in model
+        .cards
+        .iter_mut()
+        .chain(model.rows.chain.iter_mut())
+        .chain(model.rows.branch.iter_mut())
+    {
+        match &mut card.class {
+            CardClass::Sequencer(seq) => {
+                seq.step = 0;
+                seq.clock = 0.0;
+                seq.ratchet = None;
+            }
+            CardClass::Arpeggiator(arp) => {
+                arp.step = 0;
+                arp.clock = 0.0;
+            }
+            _ => {}
+        }
+    }
+}
+
+// A change of the whole patch, made once the output has faded down
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PatchSwap {
+    Init,
+    Preset(usize),
+    AllNotesOff, // Keeps the patch, only silencing it
+}
+
+// Fades the output down so tails die away instead of cutting off. `finish_swap` makes the
+// change once the fade has run.
+fn begin_swap(app: &App, model: &mut Model, swap: PatchSwap) {
+    if model.swap.is_none() {
+        model.swap = Some((app.time, swap));
+        model
+            .stream
+            .send(|audio| {
+                audio.stop_voices();
+                audio.master_volume = 0.0;
+            })
+            .unwrap();
+    }
+}
+
+// Swaps the patch under the silence, then empties the effects so nothing of the old patch
+// rings on as the output comes back up
+fn finish_swap(app: &App, model: &mut Model, swap: PatchSwap) {
+    model.swap = None;
+    // A new patch replaces whatever drop was still waiting for its beat, and starts its
+    // mod lanes from its own cards. All notes off keeps both, as it keeps the patch.
+    if swap != PatchSwap::AllNotesOff {
+        model.pending_drop = false;
+        model.lane_levels.clear();
+    }
+    match swap {
+        PatchSwap::Init => init_patch(model),
+        PatchSwap::AllNotesOff => {}
+        PatchSwap::Preset(i) => {
+            let path = model.presets[i].clone();
+            if load_patch_file(app, model, &path) {
+                model.preset = Some(i);
+            }
+        }
+    }
+    let volume = model.master_volume;
+    model
+        .stream
+        .send(move |audio| {
+            audio.clear_tails();
+            audio.master_volume = volume;
+        })
+        .unwrap();
+}
+
+// All notes off: forgets every held key and fades the output down, then empties the
+// delay, reverb and the rest under the silence before it comes back up
+fn all_notes_off(app: &App, model: &mut Model) {
+    model.held_notes.clear();
+    model.keyboard_keys.clear();
+    model.gate = false;
+    begin_swap(app, model, PatchSwap::AllNotesOff);
+}
+
+// Steps through the preset folder, wrapping at either end
+fn cycle_preset(app: &App, model: &mut Model, step: isize) {
+    let count = model.presets.len();
+    if count == 0 {
+        return;
+    }
+    let next = match model.preset {
+        Some(i) => (i as isize + step).rem_euclid(count as isize) as usize,
+        None if step > 0 => 0,
+        None => count - 1,
+    };
+    begin_swap(app, model, PatchSwap::Preset(next));
+}
+
+// Back to an empty table at the default tempo, as if freshly started. The cleared cards
+// can be brought back with undo.
+fn init_patch(model: &mut Model) {
+    push_history(model, snapshot(model));
+    model.cards.clear();
+    model.selected_card = None;
+    model.solo = None;
+    model.mod_routes.clear();
+    model.snapshot_a = None;
+    model.snapshot_b = None;
+    model.morph = 0.0;
+    model.preset = None;
+    set_bpm(model, DEFAULT_BPM);
+    rewind(model);
+    model.is_updating = true;
+}
+
+// Stop rewinds and fades out the voices; Play starts again from the top
+fn toggle_transport(model: &mut Model) {
+    model.playing = !model.playing;
+    rewind(model);
+    // Stopping has no beat left to wait for, so queued chain changes apply now
+    if model.pending_drop {
+        model.pending_drop = false;
+        model.is_updating = true;
+    }
+    if model.playing {
+        model.restart = true;
+    } else {
+        model.stream.send(|audio| audio.stop_voices()).unwrap();
+    }
+}
+
+// Plays queued MIDI events, returning whether a MIDI Start arrived
+fn handle_midi(model: &mut Model, now: f32) -> bool {
+    let mut started = false;
+    while let Ok(event) = model.midi_events.try_recv() {
+        match event {
+            MidiEvent::NoteOn { note, velocity } => note_on(model, note, velocity as f32 / 127.0),
+            MidiEvent::NoteOff { note } => note_off(model, note),
+            MidiEvent::Clock { stamp } => {
+                if let Some(last) = model.last_clock_stamp {
+                    let interval = stamp.saturating_sub(last) as f32 / 1_000_000.0;
+                    // A long gap means the clock restarted, so don't average it in
+                    if interval < CLOCK_TIMEOUT {
+                        model.pulse_interval = if model.pulse_interval > 0.0 {
+                            model.pulse_interval * 0.9 + interval * 0.1
+                        } else {
+                            interval
+                        };
+                    }
+                }
+                model.last_clock_stamp = Some(stamp);
+                model.last_clock_time = now;
+            }
+            MidiEvent::Start => {
+                model.midi_running = true;
+                if model.clock_source == ClockSource::Midi {
+                    started = true;
+                    rewind(model);
+                }
+            }
+            MidiEvent::Continue => model.midi_running = true,
+            MidiEvent::Stop => model.midi_running = false,
+        }
+    }
+    started
+}
+
+const OSC_PORT: u16 = 9000;
+
+// Addresses the synth answers to over OSC, with the range each value is clamped to
+const OSC_ADDRESSES: [(&str, f32, f32); 7] = [
+    ("/osc/hz", 20.0, 20000.0),        // Free-running pitch
+    ("/filter/cutoff", 20.0, 20000.0), // First filter card in the chain
+    ("/filter/resonance", 0.0, 1.0),
+    ("/bpm", MIN_BPM, MAX_BPM),
+    ("/volume", 0.0, 1.0),
+    ("/glide", 0.0, MAX_GLIDE_TIME), // Seconds
+    ("/swing", 0.0, MAX_SWING),
+];
+
+// Applies queued OSC messages. Each takes one number; anything else is ignored.
+fn handle_osc(model: &mut Model) {
+    let Some(receiver) = &model.osc_receiver else {
+        return;
+    };
+    let mut messages = vec![];
+    for (packet, _) in receiver.try_iter() {
+        messages.extend(packet.into_msgs());
+    }
+    for message in messages {
+        let value = match message.args.first() {
+            Some(osc::Type::Float(value)) => *value,
+            Some(osc::Type::Double(value)) => *value as f32,
+            Some(osc::Type::Int(value)) => *value as f32,
+            _ => continue,
+        };
+        let Some(&(addr, min, max)) = OSC_ADDRESSES.iter().find(|(a, ..)| *a == message.addr)
+        else {
+            continue;
+        };
+        if !value.is_finite() {
+            continue;
+        }
+        let value = value.clamp(min, max);
+        match addr {
+            "/osc/hz" => {
+                let hz = value as f64;
+                model
+                    .stream
+                    .send(move |audio| audio.drift(hz - audio.free_hz))
+                    .unwrap();
+            }
+            "/filter/cutoff" | "/filter/resonance" => {
+                // The chain holds copies, so the card itself is the one the copy was taken from
+                let filter = model
+                    .rows
+                    .chain
+                    .iter()
+                    .position(|card| matches!(card.class, CardClass::Filter(_)))
+                    .map(|pos| model.rows.chain_cards[pos]);
+                if let Some(index) = filter {
+                    // A quantized change may still be holding back copies of removed cards
+                    let card = model.cards.get_mut(index);
+                    if let Some(CardClass::Filter(filter)) = card.map(|card| &mut card.class) {
+                        if addr == "/filter/cutoff" {
+                            filter.cutoff = value;
+                        } else {
+                            filter.resonance = value;
+                        }
+                        sync_copy(model, index);
+                    }
+                }
+            }
+            "/bpm" => set_bpm(model, value),
+            "/volume" => set_master_volume(model, value),
+            "/glide" => {
+                model.glide_time = value;
+                send_glide(model);
+            }
+            "/swing" => model.swing = value,
+            _ => {}
+        }
+    }
+}
+
+// K steps the selected source's route through the destinations and back to none;
+// Shift+K steps its amount instead
+fn cycle_route(model: &mut Model, source: ModSource, amount: bool) {
+    let routes = &mut model.mod_routes;
+    match routes.iter().position(|route| route.source == source) {
+        Some(i) if amount => {
+            let next = MOD_AMOUNT_STEPS
+                .iter()
+                .position(|&a| a == routes[i].amount)
+                .map_or(0, |a| (a + 1) % MOD_AMOUNT_STEPS.len());
+            routes[i].amount = MOD_AMOUNT_STEPS[next];
+        }
+        Some(i) if routes[i].dest == ModDest::Pan => {
+            routes.remove(i);
+        }
+        Some(i) => routes[i].dest = routes[i].dest.next(),
+        None if amount => {}
+        None => routes.push(ModRoute {
+            source,
+            dest: ModDest::Pitch,
+            amount: MOD_AMOUNT_STEPS[0],
+        }),
     }
-
-    model.last_update = now;
-    handle_drag(app, model);
-    update_cards(app, model);
-    animations(app, model);
-    lerp(model);
-    update_sound(app, model);
 }
 
-fn snap_to_grid(x: f32, y: f32, grid_slots: &Vec<Point2>) -> (f32, f32) {
-    let mut nearest_slot = grid_slots[0];
-    let mut min_distance = distance(x, y, nearest_slot.x, nearest_slot.y);
+// Returns the nearest slot not already occupied by another card, if any
+fn snap_to_grid(x: f32, y: f32, grid_slots: &[Point2], occupied: &[Point2]) -> Option<(f32, f32)> {
+    let mut nearest_slot = None;
+    let mut min_distance = f32::MAX;
 
     for &slot in grid_slots.iter() {
+        let taken = occupied
+            .iter()
+            .any(|other| distance(slot.x, slot.y, other.x, other.y) < 1.0);
         let dist = distance(x, y, slot.x, slot.y);
-        if dist < min_distance {
-            nearest_slot = slot;
+        if !taken && dist < min_distance {
+            nearest_slot = Some(slot);
             min_distance = dist;
         }
     }
 
-    (nearest_slot.x, nearest_slot.y)
+    nearest_slot.map(|slot| (slot.x, slot.y))
 }
 
 fn distance(x1: f32, y1: f32, x2: f32, y2: f32) -> f32 {
@@ -427,20 +6016,330 @@ fn animations(app: &App, model: &mut Model) {
 
 fn update_cards(app: &App, model: &mut Model) {
     let win = app.window_rect();
-    if model.is_updating {
+    // A quantized change leaves the audio's copies as they are until its beat comes
+    let held = model.quantize
+        && model.playing
+        && (model.pending_drop || model.cards.iter().any(|card| card.dragging));
+    // Chain copies are what the audio hears, so a solo mutes them there
+    let muted: Vec<bool> = (0..model.cards.len())
+        .map(|i| muted_by_solo(model, i))
+        .collect();
+    let force = model.is_updating;
+    if model
+        .rows
+        .update(&model.cards, win, force, held, |i| muted[i])
+    {
+        // Routes go with the last card that could back their source
+        let cards = &model.cards;
+        model.mod_routes.retain(|route| {
+            cards
+                .iter()
+                .any(|card| ModSource::of(&card.class) == Some(route.source))
+        });
         model.hand.clear();
-        model.chain.clear();
-        for card in model.cards.iter_mut() {
-            if card.y >= win.bottom() + win.h() / 3.0 {
-                model.chain.push(card.clone());
-            } else if card.y <= win.bottom() + win.h() / 3.0 {
+        let mut hand = vec![];
+        for (i, card) in model.cards.iter().enumerate() {
+            if model.rows.regions[i] == Region::Hand {
                 model.hand.push(card.clone());
+                if !card.dragging {
+                    hand.push(i);
+                }
             }
         }
+        layout_hand(model, win, hand);
         model.is_updating = false;
     }
 }
 
+// Copies of the cards in the chain and branch rows, which the audio plays from. Dragging
+// only rebuilds them when a card crosses into another row, and parameter edits are patched
+// in, so a running sequencer keeps its place.
+#[derive(Default)]
+struct Rows {
+    regions: Vec<Region>, // Row of each card at the last rebuild, parallel to the cards
+    chain: Vec<Card>,
+    branch: Vec<Card>,        // Second chain row, heard through a mixer card
+    chain_cards: Vec<usize>,  // Index of the card each chain copy was taken from
+    branch_cards: Vec<usize>, // Index of the card each branch copy was taken from
+}
+
+impl Rows {
+    // Rebuilds the copies if a card has changed row since the last rebuild, or `force` is
+    // set, returning whether it did. While `held` only the regions move on.
+    fn update(
+        &mut self,
+        cards: &[Card],
+        win: Rect,
+        force: bool,
+        held: bool,
+        muted: impl Fn(usize) -> bool,
+    ) -> bool {
+        let regions = card_regions(cards, win);
+        if !force && regions == self.regions {
+            return false;
+        }
+        if !held {
+            (self.branch, self.chain) = partition(cards, &regions, muted);
+            self.branch_cards = row_cards(&regions, Region::Branch);
+            self.chain_cards = row_cards(&regions, Region::Chain);
+        }
+        self.regions = regions;
+        true
+    }
+
+    // The chain or branch copy taken from card `index`, if it is in either row
+    fn copy(&self, index: usize) -> Option<&Card> {
+        if let Some(pos) = self.chain_cards.iter().position(|&i| i == index) {
+            self.chain.get(pos)
+        } else {
+            let pos = self.branch_cards.iter().position(|&i| i == index)?;
+            self.branch.get(pos)
+        }
+    }
+
+    fn copy_mut(&mut self, index: usize) -> Option<&mut Card> {
+        if let Some(pos) = self.chain_cards.iter().position(|&i| i == index) {
+            self.chain.get_mut(pos)
+        } else {
+            let pos = self.branch_cards.iter().position(|&i| i == index)?;
+            self.branch.get_mut(pos)
+        }
+    }
+
+    // Takes a picked-up card's copy out of its row straight away
+    fn remove(&mut self, index: usize) {
+        if let Some(pos) = self.chain_cards.iter().position(|&i| i == index) {
+            self.chain.remove(pos);
+            self.chain_cards.remove(pos);
+        }
+        if let Some(pos) = self.branch_cards.iter().position(|&i| i == index) {
+            self.branch.remove(pos);
+            self.branch_cards.remove(pos);
+        }
+    }
+}
+
+fn card_regions(cards: &[Card], win: Rect) -> Vec<Region> {
+    cards.iter().map(|card| card_region(card, win)).collect()
+}
+
+// Copies of the cards in the branch and chain rows, in card order, with `muted` ones
+// bypassed
+fn partition(
+    cards: &[Card],
+    regions: &[Region],
+    muted: impl Fn(usize) -> bool,
+) -> (Vec<Card>, Vec<Card>) {
+    let mut branch = vec![];
+    let mut chain = vec![];
+    for (i, card) in cards.iter().enumerate() {
+        let mut copy = card.clone();
+        copy.bypassed |= muted(i);
+        match regions[i] {
+            Region::Branch => branch.push(copy),
+            Region::Chain => chain.push(copy),
+            Region::Hand => {}
+        }
+    }
+    (branch, chain)
+}
+
+// Indices of the cards in `region`, in the order `partition` copies them
+fn row_cards(regions: &[Region], region: Region) -> Vec<usize> {
+    (0..regions.len())
+        .filter(|&i| regions[i] == region)
+        .collect()
+}
+
+// Applies a parameter edit on card `index` to its chain or branch copy without a rebuild,
+// so a running sequencer or arpeggiator carries on from where it is
+fn sync_copy(model: &mut Model, index: usize) {
+    let Some(copy) = model.rows.copy_mut(index) else {
+        return;
+    };
+    let mut class = model.cards[index].class.clone();
+    // A copy of another kind is one a quantized change is still holding back
+    if std::mem::discriminant(&copy.class) != std::mem::discriminant(&class) {
+        return;
+    }
+    match (&copy.class, &mut class) {
+        (CardClass::Sequencer(live), CardClass::Sequencer(seq)) => {
+            seq.step = if live.step < seq.sequence.len() {
+                live.step
+            } else {
+                0
+            };
+            seq.clock = live.clock;
+            seq.ratchet = live.ratchet.clone();
+        }
+        (CardClass::Arpeggiator(live), CardClass::Arpeggiator(arp)) => {
+            arp.step = live.step;
+            arp.clock = live.clock;
+        }
+        _ => {}
+    }
+    copy.class = class;
+}
+
+// The row of the screen a card is in
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Region {
+    Branch,
+    Chain,
+    Hand,
+}
+
+fn card_region(card: &Card, win: Rect) -> Region {
+    region_at(card.y, win)
+}
+
+fn region_at(y: f32, win: Rect) -> Region {
+    if y >= win.y() + BRANCH_ROW_OFFSET / 2.0 {
+        Region::Branch
+    } else if y >= win.bottom() + win.h() / 3.0 {
+        Region::Chain
+    } else {
+        Region::Hand
+    }
+}
+
+// Spreads the hand cards evenly along the bottom row, keeping their left-to-right order.
+// They squeeze together rather than overflow when the row is full.
+fn layout_hand(model: &mut Model, win: Rect, mut hand: Vec<usize>) {
+    let Some(row_y) = model.grid_slots.iter().map(|slot| slot.y).reduce(f32::min) else {
+        return;
+    };
+    let row: Vec<f32> = model
+        .grid_slots
+        .iter()
+        .filter(|slot| slot.y == row_y)
+        .map(|slot| slot.x)
+        .collect();
+    let center = row.iter().sum::<f32>() / row.len() as f32;
+    let spacing = ((win.w() - 200.0) / hand.len().max(1) as f32).min(110.0);
+    hand.sort_by(|&a, &b| model.cards[a].x_targ.total_cmp(&model.cards[b].x_targ));
+    let first = center - spacing * (hand.len() as f32 - 1.0) / 2.0;
+    for (slot, &i) in hand.iter().enumerate() {
+        let card = &mut model.cards[i];
+        card.x_targ = first + slot as f32 * spacing;
+        card.y_targ = row_y;
+    }
+}
+
+// The cards as they were before the current drag, if any
+fn snapshot(model: &Model) -> Vec<Card> {
+    let mut cards = model.cards.clone();
+    if let Some(card) = model.selected_card.map(|i| &mut cards[i]) {
+        if card.dragging {
+            card.x_targ = model.drag_origin.x;
+            card.y_targ = model.drag_origin.y;
+            card.dragging = false;
+        }
+    }
+    cards
+}
+
+// Records the state before an action; a new action makes the undone ones unreachable
+fn push_history(model: &mut Model, before: Vec<Card>) {
+    model.wheel = None;
+    model.undo_stack.push_back(before);
+    if model.undo_stack.len() > MAX_HISTORY {
+        model.undo_stack.pop_front();
+    }
+    model.redo_stack.clear();
+}
+
+fn undo(model: &mut Model) {
+    if model.selected_card.is_none() {
+        if let Some(cards) = model.undo_stack.pop_back() {
+            model.redo_stack.push(model.cards.clone());
+            restore_cards(model, cards);
+        }
+    }
+}
+
+fn redo(model: &mut Model) {
+    if model.selected_card.is_none() {
+        if let Some(cards) = model.redo_stack.pop() {
+            model.undo_stack.push_back(model.cards.clone());
+            restore_cards(model, cards);
+        }
+    }
+}
+
+// Cards keep their on-screen positions so they glide back to the restored slots
+fn restore_cards(model: &mut Model, mut cards: Vec<Card>) {
+    if cards.len() == model.cards.len() {
+        for (card, current) in cards.iter_mut().zip(&model.cards) {
+            card.x = current.x;
+            card.y = current.y;
+        }
+    }
+    model.cards = cards;
+    model.wheel = None;
+    if model.solo.is_some_and(|i| i >= model.cards.len()) {
+        model.solo = None;
+    }
+    model.is_updating = true;
+}
+
+// A patch state to morph from or to
+#[derive(Clone)]
+struct Snapshot {
+    bpm: f32,
+    cards: Vec<Card>,
+}
+
+const MORPH_STEP: f32 = 0.1;
+
+fn take_snapshot(model: &Model) -> Snapshot {
+    Snapshot {
+        bpm: model.bpm,
+        cards: snapshot(model),
+    }
+}
+
+// Moves the morph between the snapshots and plays the blend. Matching cards have their
+// numbers interpolated, whole numbers snapping to the nearer side; anything that differs
+// in shape, such as the set of cards or a sequencer's length, switches over halfway.
+fn set_morph(model: &mut Model, morph: f32) {
+    let (Some(a), Some(b)) = (&model.snapshot_a, &model.snapshot_b) else {
+        return;
+    };
+    let t = morph.clamp(0.0, 1.0);
+    let mut cards = if t < 0.5 {
+        a.cards.clone()
+    } else {
+        b.cards.clone()
+    };
+    let matching = a.cards.len() == b.cards.len()
+        && a.cards
+            .iter()
+            .zip(&b.cards)
+            .all(|(a, b)| std::mem::discriminant(&a.class) == std::mem::discriminant(&b.class));
+    if matching {
+        for ((card, a), b) in cards.iter_mut().zip(&a.cards).zip(&b.cards) {
+            let (mut from, mut to) = (a.class.clone(), b.class.clone());
+            let (from, to) = (card_params(&mut from), card_params(&mut to));
+            let mut params = card_params(&mut card.class);
+            if params.len() == from.len() && params.len() == to.len() {
+                for ((param, from), to) in params.iter_mut().zip(&from).zip(&to) {
+                    param.set(from.get() + (to.get() - from.get()) * t);
+                }
+            }
+        }
+    }
+    let bpm = a.bpm + (b.bpm - a.bpm) * t;
+
+    push_history(model, snapshot(model));
+    model.morph = t;
+    if model.selected_card.is_some_and(|i| i >= cards.len()) {
+        model.selected_card = None;
+    }
+    restore_cards(model, cards);
+    set_bpm(model, bpm);
+}
+
 fn lerp(model: &mut Model) {
     for card in model.cards.iter_mut() {
         card.x += (card.x_targ - card.x) * 0.3;
@@ -448,95 +6347,848 @@ fn lerp(model: &mut Model) {
     }
 }
 
+// The card classes in signal order (left to right by slot, then top to bottom). The
+// copies themselves stay in card order, parallel to the indices they were taken from.
+fn ordered_chain(chain: &[Card]) -> Vec<&CardClass> {
+    let mut chain: Vec<&Card> = chain.iter().filter(|card| !card.bypassed).collect();
+    chain.sort_by(|a, b| {
+        a.x_targ
+            .total_cmp(&b.x_targ)
+            .then(a.y_targ.total_cmp(&b.y_targ))
+    });
+    chain.into_iter().map(|card| &card.class).collect()
+}
+
+// Everything about the chain the audio thread needs, built fresh each frame. Card values
+// arrive clamped, with beat-relative times already converted to seconds.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct SoundParams {
+    oscillators: Vec<Oscillator>,
+    noise: Option<Noise>,
+    sampler: Option<Sampler>,
+    envelope: Option<Envelope>,
+    delay: Option<Delay>,
+    filter: Option<Filter>,
+    lfo: Option<Lfo>,
+    mixer: Option<Mixer>,
+    bitcrush: Option<Bitcrush>,
+    reverb: Option<Reverb>,
+    chorus: Option<Chorus>,
+    eq: Option<Equalizer>,
+    compressor: Option<Compressor>,
+    vcas: Vec<Vca>,
+    routes: Vec<ModRoute>,
+    order: Vec<Stage>,
+    branch: Vec<Stage>,
+}
+
+impl SoundParams {
+    fn apply(self, audio: &mut Audio) {
+        for (i, state) in audio.oscillators.iter_mut().enumerate() {
+            state.active = i < self.oscillators.len();
+            if let Some(osc) = self.oscillators.get(i) {
+                let unison = osc.unison as usize;
+                state.waveform = osc.waveform;
+                state.pulse_width = osc.pulse_width;
+                state.fm = osc.modulator.as_deref().copied();
+                state.sub_level = osc.sub_level;
+                state.sub_octaves = osc.sub_octaves;
+                state.unison = unison;
+                state.detune_ratios = detune_ratios(unison, osc.detune_cents);
+                state.pan_gains = pan_gains(osc.pan);
+            }
+        }
+        if let Some(noise) = self.noise {
+            audio.noise_color = noise.color;
+            audio.noise_amplitude = noise.amplitude;
+        }
+        if let Some(sampler) = self.sampler {
+            audio.sampler.pitch = sampler.pitch;
+            audio.sampler.loop_mode = sampler.loop_mode;
+        }
+        if let Some(env) = self.envelope {
+            for voice in audio.voices.iter_mut() {
+                voice.envelope.attack = env.attack;
+                voice.envelope.decay = env.decay;
+                voice.envelope.sustain = env.sustain;
+                voice.envelope.release = env.release;
+                voice.envelope.curve = env.curve;
+                voice.envelope.velocity_amount = env.velocity_amount;
+            }
+        }
+        if let Some(delay) = self.delay {
+            audio.delay.delay_time = delay.delay_time;
+            audio.delay.feedback = delay.feedback;
+            audio.delay.wet = delay.wet;
+            audio.delay.ping_pong = delay.ping_pong;
+            audio.delay.frozen = delay.frozen;
+        }
+        match self.filter {
+            Some(filter) => audio
+                .filter
+                .set_params(filter.cutoff, filter.resonance, filter.mode),
+            // Don't let old resonance ring out when the filter comes back
+            None => audio.filter.reset(),
+        }
+        audio.lfo.active = self.lfo.is_some();
+        if let Some(lfo) = self.lfo {
+            audio.lfo.rate = lfo.rate;
+            audio.lfo.depth = lfo.depth;
+            audio.lfo.shape = lfo.shape;
+        }
+        if let Some(mixer) = self.mixer {
+            audio.mixer_gains = [mixer.gain_a, mixer.gain_b];
+        }
+        if let Some(crush) = self.bitcrush {
+            audio.crusher.bits = crush.bits;
+            audio.crusher.downsample = crush.downsample;
+            audio.crusher.counter %= crush.downsample;
+        }
+        if let Some(reverb) = self.reverb {
+            audio.reverb.room_size = reverb.room_size;
+            audio.reverb.damping = reverb.damping;
+            audio.reverb.wet = reverb.wet;
+        }
+        if let Some(chorus) = self.chorus {
+            audio.chorus.rate = chorus.rate;
+            audio.chorus.depth = chorus.depth;
+            audio.chorus.mix = chorus.mix;
+            audio.chorus.feedback = chorus.feedback;
+        }
+        match self.eq {
+            Some(eq) => audio.eq.set_params([eq.low, eq.mid, eq.high], eq.mid_freq),
+            None => audio.eq.reset(),
+        }
+        for (state, vca) in audio.vcas.iter_mut().zip(&self.vcas) {
+            state.target = vca.gain;
+        }
+        if let Some(comp) = self.compressor {
+            audio.compressor.threshold = comp.threshold;
+            audio.compressor.ratio = comp.ratio;
+            audio.compressor.attack = comp.attack;
+            audio.compressor.release = comp.release;
+            audio.compressor.makeup = comp.makeup;
+        }
+        audio.routes = self.routes;
+        audio.order = self.order;
+        audio.branch = self.branch;
+    }
+}
+
+// The master clock as the sequencers see it on one frame
+struct StepClock {
+    beat_crossed: bool,
+    bar_start: bool,
+    beat_phase: f32,    // Progress through the current master step, 0..1
+    beat_delta: f32,    // Beats the clock moved on this frame
+    step_duration: f32, // Length of the current master step in seconds, swing included
+    beat_seconds: f32,
+    listening: bool, // Steps follow onsets in the input rather than the clock
+    onset: bool,
+}
+
+// Moves each sequencer on by one frame. Returns the notes that gate an envelope, each with
+// how long ago in seconds its step fell and the time until the next one could. Mod lanes
+// record the values they step to in `lane_levels` instead.
+fn step_sequencers<'a>(
+    sequencers: impl Iterator<Item = &'a mut Sequencer>,
+    clock: &StepClock,
+    rng: &mut StdRng,
+    lane_levels: &mut Vec<(SeqTarget, f32)>,
+) -> Vec<(f64, f32, f32)> {
+    let mut triggers = vec![];
+    for seq in sequencers {
+        // How far the step clock has already run past its last step, in seconds
+        let step_seconds = if seq.clock_div == 1.0 {
+            clock.step_duration
+        } else {
+            clock.beat_seconds / seq.clock_div
+        };
+        let late = |ran: f32| ran.max(0.0) * step_seconds;
+        // While listening, each onset plays the next step in place of the clock
+        let stepped = if clock.listening {
+            clock.onset
+        } else {
+            seq.advance_clock(
+                clock.beat_crossed,
+                clock.bar_start,
+                clock.beat_phase,
+                clock.beat_delta,
+            )
+        };
+        if stepped {
+            // Any repeats left over from the last step are dropped, and onset steps
+            // have no clock to spread repeats over
+            seq.ratchet = None;
+            if seq.target != SeqTarget::Pitch {
+                // Mod lanes hold their value through rests and have no notes to repeat
+                if let Some(value) = seq.next_step(rng) {
+                    let target = seq.target;
+                    lane_levels.retain(|&(t, _)| t != target);
+                    lane_levels.push((target, value));
+                }
+                continue;
+            }
+            let count = if clock.listening {
+                1
+            } else {
+                seq.ratchet_count()
+            };
+            // Rests leave the envelope alone
+            if let Some(hz) = seq.next_step(rng) {
+                let ago = if clock.listening {
+                    0.0
+                } else {
+                    late(seq.clock)
+                };
+                triggers.push((hz as f64, ago, step_seconds / count as f32));
+                if count > 1 {
+                    seq.ratchet = Some(Ratchet {
+                        hz: hz as f64,
+                        count,
+                        fired: 1,
+                    });
+                }
+            }
+        }
+        if let Some(ratchet) = &mut seq.ratchet {
+            let due = ratchet.fired as f32 / ratchet.count as f32;
+            if seq.clock >= due {
+                let spacing = step_seconds / ratchet.count as f32;
+                triggers.push((ratchet.hz, late(seq.clock - due), spacing));
+                ratchet.fired += 1;
+                if ratchet.fired >= ratchet.count {
+                    seq.ratchet = None;
+                }
+            }
+        }
+    }
+    triggers
+}
+
 fn update_sound(app: &App, model: &mut Model) {
     let hz_increment = 1.0 * (app.time as f64).sin();
-    let beat_duration = 60.0 / model.bpm as f64;
+    let beat_duration = 60.0 / model.tempo as f64;
+    let beat_crossed = model.beat_crossed;
+    let onset = model.onset;
 
-    let sequencer_index = model
-        .chain
-        .iter()
-        .position(|card| matches!(card.class, CardClass::Sequencer(_)));
+    // Queued first, as nothing this frame falls before the beat
+    if beat_crossed && model.metronome_on {
+        let accent = model.beat_step.is_multiple_of(model.beats_per_bar);
+        schedule(model, NoteEvent::Click { accent }, model.beat_time);
+    }
 
-    let oscillator_index = model
-        .chain
-        .iter()
-        .position(|card| matches!(card.class, CardClass::Oscillator(_)));
+    // Each stage is tagged with whether it belongs to the branch row
+    let mut stages: Vec<(Stage, bool)> = vec![];
+    let used = |stages: &[(Stage, bool)], stage| stages.iter().any(|&(s, _)| s == stage);
+    let mut params = SoundParams::default();
+    let mut has_sequencer = false;
+    let mut has_note_sequencer = false;
+    let mut has_arpeggiator = false;
+    // Frequencies of the notes that gate an envelope this frame, each with how long ago in
+    // seconds its step fell and the time until the next one could
+    let mut triggers: Vec<(f64, f32, f32)> = vec![];
+    let beat_seconds = beat_duration as f32;
 
-    let envelope_index = model
-        .chain
-        .iter()
-        .position(|card| matches!(card.class, CardClass::Envelope(_)));
+    let main = ordered_chain(&model.rows.chain)
+        .into_iter()
+        .map(|c| (c, false));
+    let branch = ordered_chain(&model.rows.branch)
+        .into_iter()
+        .map(|c| (c, true));
+    for (class, in_branch) in main.chain(branch) {
+        match class {
+            CardClass::Oscillator(osc) if params.oscillators.len() < MAX_OSCILLATORS => {
+                let index = params.oscillators.len();
+                let mut osc = osc.clone();
+                osc.pulse_width = osc.pulse_width.clamp(MIN_PULSE_WIDTH, MAX_PULSE_WIDTH);
+                if let Some(fm) = osc.modulator.as_deref_mut() {
+                    fm.ratio = fm.ratio.clamp(MIN_FM_RATIO, MAX_FM_RATIO);
+                    fm.index = fm.index.clamp(0.0, MAX_FM_INDEX);
+                }
+                osc.sub_level = osc.sub_level.clamp(0.0, 1.0);
+                osc.sub_octaves = osc.sub_octaves.clamp(1, MAX_SUB_OCTAVES);
+                osc.unison = osc.unison.clamp(1, MAX_UNISON as u8);
+                osc.detune_cents = osc.detune_cents.clamp(0.0, MAX_DETUNE_CENTS);
+                params.oscillators.push(osc);
+                stages.push((Stage::Oscillator(index), in_branch));
+            }
+            // Oscillators beyond the voice limit are ignored
+            CardClass::Oscillator(_) => {}
+            CardClass::Noise(noise) => {
+                params.noise = Some(noise.clone());
+                stages.push((Stage::Noise, in_branch));
+            }
+            CardClass::Sampler(sampler) if !used(&stages, Stage::Sampler) => {
+                params.sampler = Some(Sampler {
+                    path: sampler.path.clone(),
+                    loop_mode: sampler.loop_mode,
+                    pitch: sampler.pitch.clamp(MIN_SAMPLER_PITCH, MAX_SAMPLER_PITCH),
+                });
+                stages.push((Stage::Sampler, in_branch));
+            }
+            // Only one sample plays at a time
+            CardClass::Sampler(_) => {}
+            CardClass::Envelope(env) => {
+                if !used(&stages, Stage::Envelope) {
+                    // Stage lengths on the card are fractions of a beat
+                    let beat = beat_duration as f32;
+                    params.envelope = Some(Envelope {
+                        attack: env.attack * beat,
+                        decay: env.decay * beat,
+                        sustain: env.sustain,
+                        release: env.release * beat,
+                        curve: env.curve,
+                        velocity_amount: env.velocity_amount.clamp(0.0, 1.0),
+                    });
+                }
+                stages.push((Stage::Envelope, in_branch));
+            }
+            CardClass::Delay(delay) if !used(&stages, Stage::Delay) => {
+                // Synced delays follow the tempo; the audio thread resizes its buffer to match
+                let delay_time = match delay.sync {
+                    Some(division) => division.beats() * beat_duration as f32,
+                    None => delay.delay_time,
+                };
+                params.delay = Some(Delay {
+                    delay_time,
+                    // Unity feedback or more would build up without bound
+                    feedback: delay.feedback.clamp(0.0, 0.95),
+                    wet: delay.wet,
+                    sync: None,
+                    ping_pong: delay.ping_pong,
+                    frozen: delay.frozen,
+                });
+                stages.push((Stage::Delay, in_branch));
+            }
+            // Only one delay line is processed at a time
+            CardClass::Delay(_) => {}
+            CardClass::Filter(filter) if !used(&stages, Stage::Filter) => {
+                params.filter = Some(filter.clone());
+                stages.push((Stage::Filter, in_branch));
+            }
+            // Only one filter is processed at a time
+            CardClass::Filter(_) => {}
+            CardClass::Lfo(lfo) if params.lfo.is_none() => {
+                params.routes.push(ModRoute {
+                    source: ModSource::Lfo,
+                    dest: lfo.target,
+                    amount: 1.0,
+                });
+                params.lfo = Some(Lfo {
+                    rate: lfo.rate.clamp(MIN_LFO_RATE, MAX_LFO_RATE),
+                    depth: lfo.depth.clamp(0.0, 1.0),
+                    shape: lfo.shape,
+                    target: lfo.target,
+                });
+            }
+            // Only one LFO modulates at a time
+            CardClass::Lfo(_) => {}
+            CardClass::Mixer(mixer) if !in_branch && !used(&stages, Stage::Mixer) => {
+                params.mixer = Some(mixer.clone());
+                stages.push((Stage::Mixer, false));
+            }
+            // Only one mixer, in the main row, joins the branch in
+            CardClass::Mixer(_) => {}
+            CardClass::Bitcrush(crush) if !used(&stages, Stage::Bitcrush) => {
+                params.bitcrush = Some(Bitcrush {
+                    bits: crush.bits.clamp(1, 16),
+                    downsample: crush.downsample.clamp(1, MAX_DOWNSAMPLE),
+                });
+                stages.push((Stage::Bitcrush, in_branch));
+            }
+            // Only one bitcrusher is processed at a time
+            CardClass::Bitcrush(_) => {}
+            CardClass::Vca(vca) if params.vcas.len() < MAX_VCAS => {
+                stages.push((Stage::Vca(params.vcas.len()), in_branch));
+                params.vcas.push(Vca {
+                    gain: vca.gain.clamp(0.0, MAX_VCA_GAIN),
+                });
+            }
+            CardClass::Vca(_) => {}
+            CardClass::Reverb(reverb) if !used(&stages, Stage::Reverb) => {
+                params.reverb = Some(Reverb {
+                    room_size: reverb.room_size.clamp(0.0, 1.0),
+                    damping: reverb.damping.clamp(0.0, 1.0),
+                    wet: reverb.wet.clamp(0.0, 1.0),
+                });
+                stages.push((Stage::Reverb, in_branch));
+            }
+            // Only one reverb is processed at a time
+            CardClass::Reverb(_) => {}
+            CardClass::Chorus(chorus) if !used(&stages, Stage::Chorus) => {
+                params.chorus = Some(Chorus {
+                    rate: chorus.rate.clamp(MIN_CHORUS_RATE, MAX_CHORUS_RATE),
+                    depth: chorus.depth.clamp(0.0, 1.0),
+                    mix: chorus.mix.clamp(0.0, 1.0),
+                    feedback: chorus.feedback.clamp(0.0, MAX_CHORUS_FEEDBACK),
+                });
+                stages.push((Stage::Chorus, in_branch));
+            }
+            // Only one chorus is processed at a time
+            CardClass::Chorus(_) => {}
+            CardClass::Eq(eq) if !used(&stages, Stage::Eq) => {
+                let gain = |g: f32| g.clamp(-EQ_MAX_GAIN, EQ_MAX_GAIN);
+                params.eq = Some(Equalizer {
+                    low: gain(eq.low),
+                    mid: gain(eq.mid),
+                    high: gain(eq.high),
+                    mid_freq: eq.mid_freq.clamp(EQ_MIN_MID_FREQ, EQ_MAX_MID_FREQ),
+                });
+                stages.push((Stage::Eq, in_branch));
+            }
+            // Only one EQ is processed at a time
+            CardClass::Eq(_) => {}
+            CardClass::Compressor(comp) if !used(&stages, Stage::Compressor) => {
+                params.compressor = Some(Compressor {
+                    threshold: comp.threshold.clamp(MIN_COMP_THRESHOLD, 0.0),
+                    ratio: comp.ratio.clamp(1.0, MAX_COMP_RATIO),
+                    attack: comp.attack.clamp(MIN_COMP_ATTACK, MAX_COMP_ATTACK),
+                    release: comp.release.clamp(MIN_COMP_RELEASE, MAX_COMP_RELEASE),
+                    makeup: comp.makeup.clamp(0.0, MAX_COMP_MAKEUP),
+                });
+                stages.push((Stage::Compressor, in_branch));
+            }
+            // Only one compressor is processed at a time
+            CardClass::Compressor(_) => {}
+            CardClass::Sequencer(seq) => {
+                has_sequencer = true;
+                has_note_sequencer |= seq.target == SeqTarget::Pitch;
+            }
+            CardClass::Arpeggiator(_) => has_arpeggiator = true,
+        }
+    }
+    // Matrix routes only sound while the card behind their source is in the chain
+    let has_envelope = used(&stages, Stage::Envelope);
+    let has_lfo = params.lfo.is_some();
+    params
+        .routes
+        .extend(model.mod_routes.iter().filter_map(|route| {
+            let backed = match route.source {
+                ModSource::Lfo => has_lfo,
+                ModSource::Envelope => has_envelope,
+            };
+            backed.then_some(ModRoute {
+                amount: route.amount.clamp(-1.0, 1.0),
+                ..*route
+            })
+        }));
+    let (branch, order): (Vec<_>, Vec<_>) = stages.into_iter().partition(|&(_, b)| b);
+    params.order = order.into_iter().map(|(stage, _)| stage).collect();
+    params.branch = branch.into_iter().map(|(stage, _)| stage).collect();
 
-    let delay_index = model
+    if has_sequencer {
+        // Every sequencer plays on its own clock, each note taking a voice of its own
+        let clock = StepClock {
+            beat_crossed,
+            bar_start: beat_crossed && model.beat_step.is_multiple_of(model.beats_per_bar),
+            beat_phase: model.beat_time / model.step_duration,
+            beat_delta: model.beat_delta,
+            step_duration: model.step_duration,
+            beat_seconds,
+            listening: model.listening,
+            onset,
+        };
+        let sequencers = model
+            .rows
+            .chain
+            .iter_mut()
+            .chain(model.rows.branch.iter_mut())
+            .filter(|card| !card.bypassed)
+            .filter_map(|card| match &mut card.class {
+                CardClass::Sequencer(seq) => Some(seq),
+                _ => None,
+            });
+        triggers.extend(step_sequencers(
+            sequencers,
+            &clock,
+            &mut model.rng,
+            &mut model.lane_levels,
+        ));
+    }
+
+    // Mod lanes set their destination outright, and go unheard while it's missing
+    let lanes: Vec<SeqTarget> = model
+        .rows
         .chain
         .iter()
-        .position(|card| matches!(card.class, CardClass::Delay(_)));
+        .chain(model.rows.branch.iter())
+        .filter(|card| !card.bypassed)
+        .filter_map(|card| match &card.class {
+            CardClass::Sequencer(seq) => Some(seq.target),
+            _ => None,
+        })
+        .collect();
+    // A lane taken out of the chain lets go, so one put back waits for its first step
+    model
+        .lane_levels
+        .retain(|(target, _)| lanes.contains(target));
+    for &(target, level) in model.lane_levels.iter() {
+        match target {
+            SeqTarget::Pitch => {}
+            SeqTarget::Cutoff => {
+                if let Some(filter) = &mut params.filter {
+                    filter.cutoff = level;
+                }
+            }
+            SeqTarget::DelayMix => {
+                if let Some(delay) = &mut params.delay {
+                    delay.wet = level;
+                }
+            }
+        }
+    }
 
-    if let Some(_) = oscillator_index {
-        model.stream.send(|audio| audio.playing = true).unwrap();
-    } else {
-        model.stream.send(|audio| audio.playing = false).unwrap();
+    // Files are read when a sampler first names them, and the samples handed over whole
+    if let Some(sampler) = &params.sampler {
+        if model.sample_path.as_ref() != Some(&sampler.path) {
+            model.sample_path = Some(sampler.path.clone());
+            let (buffer, rate) = load_sample(&sampler.path).unwrap_or_else(|err| {
+                eprintln!("Couldn't load {}: {}", sampler.path, err);
+                (vec![], 0)
+            });
+            model
+                .stream
+                .send(move |audio| audio.sampler.load(buffer, rate as f32))
+                .unwrap();
+        }
     }
 
-    if let Some(index) = sequencer_index {
-        if let Some(CardClass::Sequencer(seq)) =
-            model.chain.get_mut(index).map(|card| &mut card.class)
-        {
-            if model.beat_time == 0.0 {
-                let next_value = seq.next_value();
-                let new_hz = next_value as f64;
+    // Settings only cross to the audio thread when something has changed
+    if params != model.sent_params {
+        model.sent_params = params.clone();
+        model.stream.send(move |audio| params.apply(audio)).unwrap();
+    }
 
-                model
-                    .stream
-                    .send(move |audio| audio.hz = 440.0 * new_hz)
-                    .unwrap();
-            }
+    if has_arpeggiator {
+        // Held keys feed the arpeggiators instead of playing directly
+        let beat_delta = model.beat_delta;
+        let mut notes = vec![];
+        let arpeggiators = model
+            .rows
+            .chain
+            .iter_mut()
+            .chain(model.rows.branch.iter_mut())
+            .filter(|card| !card.bypassed)
+            .filter_map(|card| match &mut card.class {
+                CardClass::Arpeggiator(arp) => Some(arp),
+                _ => None,
+            });
+        for arp in arpeggiators {
+            let note = arp.next_note(&model.held_notes, beat_delta, &mut model.rng);
+            // The clock keeps what it ran past the step
+            let spacing = arp.rate.beats() * beat_seconds;
+            notes.extend(note.map(|note| (note, arp.clock * beat_seconds, spacing)));
         }
-    } else {
+        triggers.extend(
+            notes
+                .into_iter()
+                .map(|(note, ago, spacing)| (midi_to_hz(note), ago, spacing)),
+        );
+    }
+
+    // Each trigger starts a voice whose envelope opens on it, so steps gate the envelope
+    // wherever the cards sit in the chain. They're timed to the sample the step fell on,
+    // oldest first so none waits behind a later one.
+    let mut triggers: Vec<(f64, f32, f32)> = triggers
+        .into_iter()
+        .map(|(hz, ago, spacing)| {
+            let (delay, velocity) = humanize(model, spacing);
+            (hz, ago - delay, velocity)
+        })
+        .collect();
+    triggers.sort_by(|a, b| b.1.total_cmp(&a.1));
+    for (hz, ago, velocity) in triggers {
+        schedule(model, NoteEvent::On { hz, velocity }, ago);
+    }
+
+    if !has_note_sequencer && !has_arpeggiator && !model.gate {
+        // Free-running drift and beat retriggering give way to notes played by hand
         model
             .stream
-            .send(move |audio| audio.hz += hz_increment)
+            .send(move |audio| audio.drift(hz_increment))
             .unwrap();
+        // Without a sequencer the envelope retriggers on every beat, or on every onset
+        // while listening
+        if beat_crossed && !model.listening {
+            schedule(model, NoteEvent::Retrigger, model.beat_time);
+        } else if onset {
+            schedule(model, NoteEvent::Retrigger, 0.0);
+        }
     }
+}
 
-    if let Some(index) = envelope_index {
-        if let Some(CardClass::Envelope(env)) =
-            model.chain.get_mut(index).map(|card| &mut card.class)
-        {
-            let Envelope {
-                attack,
-                decay,
-                sustain,
-                release,
-            } = env;
-            let envelope = if model.beat_time < beat_duration as f32 * *attack {
-                (model.beat_time / (beat_duration as f32 * *attack)).min(1.0)
-            } else if model.beat_time < beat_duration as f32 * (*attack + *decay) {
-                let decay_time = model.beat_time - beat_duration as f32 * *attack;
-                *sustain + (1.0 - *sustain) * (1.0 - decay_time / (beat_duration as f32 * *decay))
-            } else if model.beat_time < beat_duration as f32 * (*attack + *decay + *release) {
-                let release_time = model.beat_time - beat_duration as f32 * (*attack + *decay);
-                *sustain * (1.0 - release_time / (beat_duration as f32 * *release))
-            } else {
-                0.0
-            };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            model
-                .stream
-                .send(move |audio| audio.envelope = envelope)
-                .unwrap();
+    #[test]
+    fn beat_crosses_once_per_beat_at_awkward_frame_rates() {
+        let step_duration = 0.5;
+        let delta = 1.0 / 59.94;
+        let mut beat_time = 0.0;
+        let mut crossings = vec![];
+        for frame in 0..600 {
+            beat_time += delta;
+            if let Some(carried) = cross_beat(beat_time, step_duration) {
+                beat_time = carried;
+                crossings.push(frame);
+            }
         }
-    } else {
-        model
-            .stream
-            .send(move |audio| audio.envelope = 1.0)
+        // 600 frames is just over ten seconds, so twenty beats
+        assert_eq!(crossings.len(), 20);
+        for pair in crossings.windows(2) {
+            let frames = pair[1] - pair[0];
+            assert!((29..=31).contains(&frames), "beats {} frames apart", frames);
+        }
+    }
+
+    #[test]
+    fn soft_clip_is_transparent_below_the_knee_and_bounded_above_it() {
+        for x in [-0.5, -0.1, 0.0, 0.25, SOFT_CLIP_KNEE] {
+            assert_eq!(soft_clip(x, 1.0), x);
+        }
+        let signal = (0..1000).map(|i| 4.0 * (i as f32 * 0.05).sin());
+        for x in signal {
+            for drive in DRIVE_STEPS {
+                let y = soft_clip(x, drive);
+                assert!(
+                    (-1.0..=1.0).contains(&y),
+                    "{} at drive {} gave {}",
+                    x,
+                    drive,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn a4_is_440_hz() {
+        assert_eq!(midi_to_hz(69), 440.0);
+        assert_eq!(note_name(69), "A4");
+        assert!((midi_to_hz(81) - 880.0).abs() < 1e-9);
+        assert!((note_to_hz(69, 432.0) - 432.0).abs() < 1e-9);
+    }
+
+    fn sequencer(sequence: Vec<u8>) -> Sequencer {
+        let len = sequence.len();
+        Sequencer {
+            sequence,
+            gates: vec![true; len],
+            probabilities: vec![1.0; len],
+            ratchets: vec![1; len],
+            clock_div: 1.0,
+            clock: 0.0,
+            ratchet: None,
+            step: 0,
+            root_hz: 440.0,
+            scale: Scale::Chromatic,
+            target: SeqTarget::Pitch,
+        }
+    }
+
+    #[test]
+    fn step_probability_never_or_always_triggers() {
+        let mut rng = StdRng::seed_from_u64(DEFAULT_SEED);
+        let mut seq = sequencer(vec![60, 64, 67, 72]);
+        seq.probabilities = vec![0.0; 4];
+        for _ in 0..101 {
+            assert_eq!(seq.next_step(&mut rng), None);
+        }
+        // Skipped steps still move the sequence on
+        assert_eq!(seq.step, 1);
+        seq.probabilities = vec![1.0; 4];
+        for i in 0..100 {
+            let note = seq.sequence[(i + 1) % 4];
+            assert_eq!(seq.next_step(&mut rng), Some(midi_to_hz(note) as f32));
+        }
+    }
+
+    #[test]
+    fn rows_rebuild_only_when_a_card_changes_row_and_copy_where_cards_sit() {
+        let win = Rect::from_w_h(1200.0, 800.0);
+        // Heights in the hand, chain and branch rows
+        let heights = [-300.0, 0.0, 200.0];
+        let mut cards: Vec<Card> = (0..6)
+            .map(|i| {
+                let class = CardClass::Vca(Vca { gain: i as f32 });
+                Card::new(i as f32 * 100.0, heights[i % 3], class)
+            })
+            .collect();
+        let mut rows = Rows::default();
+        assert!(rows.update(&cards, win, true, false, |_| false));
+        let mut rng = StdRng::seed_from_u64(DEFAULT_SEED);
+        for _ in 0..200 {
+            // A drag moves the card under the cursor, rebuilding only as it changes row
+            let i = rng.gen_range(0..cards.len());
+            let x = rng.gen_range(-500.0..500.0);
+            let y = heights[rng.gen_range(0..3)] + rng.gen_range(-20.0..20.0);
+            let (chain, branch) = (rows.chain.clone(), rows.branch.clone());
+            let crossed = region_at(y, win) != rows.regions[i];
+            let card = &mut cards[i];
+            card.dragging = true;
+            (card.x, card.x_targ, card.y, card.y_targ) = (x, x, y, y);
+            assert_eq!(rows.update(&cards, win, false, false, |_| false), crossed);
+            if !crossed {
+                assert_eq!((&rows.chain, &rows.branch), (&chain, &branch));
+            }
+            // A drop always rebuilds, leaving each copy where its card now sits
+            cards[i].dragging = false;
+            assert!(rows.update(&cards, win, true, false, |_| false));
+            for (region, copies, indices) in [
+                (Region::Chain, &rows.chain, &rows.chain_cards),
+                (Region::Branch, &rows.branch, &rows.branch_cards),
+            ] {
+                let in_row: Vec<usize> = (0..cards.len())
+                    .filter(|&i| card_region(&cards[i], win) == region)
+                    .collect();
+                assert_eq!(indices, &in_row);
+                for (copy, &index) in copies.iter().zip(indices) {
+                    assert_eq!(copy, &cards[index]);
+                    assert_eq!(rows.copy(index), Some(copy));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn event_scheduled_mid_buffer_lands_on_its_frame() {
+        let sample_rate = 48000.0;
+        let buffer_start = 1024;
+        let (mut producer, mut consumer) = rtrb::RingBuffer::<TimedEvent>::new(8);
+        let on = NoteEvent::On {
+            hz: 440.0,
+            velocity: 1.0,
+        };
+        // The queue is kept oldest first. A note already late when it arrives plays on the
+        // buffer's first frame.
+        let late = event_frame(buffer_start, -0.01, sample_rate);
+        producer
+            .push(TimedEvent {
+                frame: late,
+                kind: on,
+            })
             .unwrap();
+        let frame = event_frame(buffer_start, 300.0 / sample_rate, sample_rate);
+        producer.push(TimedEvent { frame, kind: on }).unwrap();
+        let mut played = vec![];
+        for i in 0..512 {
+            while next_due(&mut consumer, buffer_start + i).is_some() {
+                played.push(i);
+            }
+        }
+        assert_eq!(played, [0, 300]);
     }
-    if let Some(index) = delay_index {
-        if let Some(CardClass::Delay(delay)) =
-            model.chain.get_mut(index).map(|card| &mut card.class)
-        {
-            // update_delay(delay, model);
+
+    #[test]
+    fn delay_length_follows_the_sample_rate() {
+        for sample_rate in [44100.0, 96000.0] {
+            let mut delay = DelayState::new();
+            delay.buffers = delay_buffers(sample_rate);
+            delay.delay_time = 0.3;
+            delay.feedback = 0.0;
+            delay.wet = 1.0;
+            delay.prepare(sample_rate);
+            let len = (0.3 * sample_rate).round() as usize;
+            assert_eq!(delay.len, len);
+            // An impulse comes back out exactly that many samples later
+            let echo = (0..len + 10)
+                .map(|i| delay.process([if i == 0 { 1.0 } else { 0.0 }; 2])[0])
+                .position(|x| x != 0.0);
+            assert_eq!(echo, Some(len));
+        }
+    }
+
+    #[test]
+    fn delay_time_changes_fade_between_reads() {
+        let sample_rate = 48000.0;
+        let mut delay = DelayState::new();
+        delay.buffers = delay_buffers(sample_rate);
+        delay.delay_time = 0.3;
+        delay.feedback = 0.0;
+        delay.wet = 1.0;
+        let sine = |i: usize| (i as f32 * 50.0 * TAU / sample_rate).sin();
+        let mut out = vec![];
+        for block in 0..60 {
+            // Moving a synced delay's time by half a 50 Hz cycle
+            if block == 40 {
+                delay.delay_time = 0.31;
+            }
+            delay.prepare(sample_rate);
+            out.extend((0..512).map(|i| delay.process([sine(block * 512 + i); 2])[0]));
+        }
+        assert_eq!(delay.len, (0.31 * sample_rate).round() as usize);
+        // No step in the output bigger than the sine's own, plus the fade's slope
+        let slope = out
+            .windows(2)
+            .map(|w| (w[1] - w[0]).abs())
+            .fold(0.0, f32::max);
+        assert!(slope < 0.01, "{slope}");
+    }
+
+    #[test]
+    fn envelope_retriggers_on_gated_steps_only() {
+        let mut rng = StdRng::seed_from_u64(DEFAULT_SEED);
+        let mut seq = sequencer(vec![60, 62, 64, 65]);
+        seq.gates = vec![true, false, true, true];
+        let mut envelope = dsp::Envelope::new();
+        let step_duration = 0.5;
+        let delta = 1.0 / 60.0;
+        let mut beat_time = 0.0;
+        let mut beat = 0;
+        let mut lane_levels = vec![];
+        let mut retriggered = vec![];
+        for _ in 0..(8.0 / delta) as usize {
+            beat_time += delta;
+            let carried = cross_beat(beat_time, step_duration);
+            if let Some(carried) = carried {
+                beat_time = carried;
+                beat += 1;
+            }
+            // Left at its defaults the envelope runs longer than a step, so every note
+            // retriggers it out of its decay or release
+            let before = envelope.stage;
+            let clock = StepClock {
+                beat_crossed: carried.is_some(),
+                bar_start: false,
+                beat_phase: beat_time / step_duration,
+                beat_delta: delta / step_duration,
+                step_duration,
+                beat_seconds: step_duration,
+                listening: false,
+                onset: false,
+            };
+            let seqs = std::iter::once(&mut seq);
+            for _ in step_sequencers(seqs, &clock, &mut rng, &mut lane_levels) {
+                envelope.gate_on(false, 1.0);
+            }
+            for _ in 0..800 {
+                envelope.next(delta / 800.0);
+            }
+            if envelope.stage == EnvStage::Attack && before != EnvStage::Attack {
+                retriggered.push(beat);
+            }
         }
+        // Steps fall on beats 1 to 15; the second of every four is a rest
+        let gated: Vec<usize> = (1..16).filter(|beat| (beat - 1) % 4 != 1).collect();
+        assert_eq!(retriggered, gated);
+    }
+
+    #[test]
+    fn repair_fills_in_the_missing_chances_and_ratchets() {
+        let mut seq = sequencer(vec![60, 62, 64]);
+        seq.gates = vec![true];
+        seq.probabilities = vec![];
+        seq.ratchets = vec![2];
+        seq.repair();
+        assert_eq!(seq.gates, [true; 3]);
+        assert_eq!(seq.probabilities, [1.0; 3]);
+        assert_eq!(seq.ratchets, [2, 1, 1]);
+        // Listing the parameters leaves the card as it is
+        let mut class = CardClass::Sequencer(seq);
+        let before = class.clone();
+        assert_eq!(card_params(&mut class).len(), 3 * 3 + 2);
+        assert_eq!(class, before);
     }
 }